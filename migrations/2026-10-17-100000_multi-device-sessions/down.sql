@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS idx_connections_active_user;
+DROP INDEX IF EXISTS idx_connections_session;
+ALTER TABLE connections DROP COLUMN IF EXISTS id_session;
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+ALTER TABLE connections ADD COLUMN IF NOT EXISTS id_session uuid;
+
+UPDATE connections SET id_session = id_connection WHERE id_session IS NULL;
+
+ALTER TABLE connections ALTER COLUMN id_session SET NOT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_connections_session ON connections (id_session);
+CREATE INDEX IF NOT EXISTS idx_connections_active_user ON connections (id_user) WHERE ended_at IS NULL;
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE connections DROP COLUMN IF EXISTS refreshed_at;
+ALTER TABLE connections DROP COLUMN IF EXISTS device_label;
+ALTER TABLE connections DROP COLUMN IF EXISTS ip_address;
+ALTER TABLE connections DROP COLUMN IF EXISTS user_agent;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE connections ADD COLUMN IF NOT EXISTS user_agent text;
+ALTER TABLE connections ADD COLUMN IF NOT EXISTS ip_address text;
+ALTER TABLE connections ADD COLUMN IF NOT EXISTS device_label varchar(100);
+ALTER TABLE connections ADD COLUMN IF NOT EXISTS refreshed_at timestamp;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS security_events;
+ALTER TABLE connections DROP COLUMN IF EXISTS rotated_at;
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+ALTER TABLE connections ADD COLUMN IF NOT EXISTS rotated_at timestamp;
+
+CREATE TABLE IF NOT EXISTS security_events
+(
+    id_security_event uuid primary key,
+    id_user           uuid        not null,
+    event_type        varchar(50) not null,
+    id_session        uuid,
+    ip_address        text,
+    user_agent        text,
+    created_at        timestamp default now(),
+    CONSTRAINT fk_security_events_user FOREIGN KEY (id_user) references rl_users (id_user)
+);
@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE IF NOT EXISTS login_attempts
+(
+    attempt_key    varchar(300) primary key,
+    failed_count   int not null default 0,
+    last_failed_at timestamp,
+    locked_until   timestamp
+);
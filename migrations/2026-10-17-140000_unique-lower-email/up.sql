@@ -0,0 +1,26 @@
+-- Your SQL goes here
+-- Accounts whose emails only differ by case or surrounding spaces have to be
+-- merged or renamed by hand first; abort before touching any row.
+DO $$
+DECLARE
+    conflicts text;
+BEGIN
+    SELECT string_agg(normalized || ': ' || ids, E'\n' ORDER BY normalized)
+    INTO conflicts
+    FROM (
+        SELECT lower(trim(email)) AS normalized,
+               string_agg(id_user::text || ' <' || email || '>', ', ' ORDER BY email) AS ids
+        FROM rl_users
+        GROUP BY lower(trim(email))
+        HAVING count(*) > 1
+    ) duplicates;
+
+    IF conflicts IS NOT NULL THEN
+        RAISE EXCEPTION E'Emails that only differ by case must be resolved before this migration:\n%', conflicts;
+    END IF;
+END
+$$;
+
+UPDATE rl_users SET email = lower(trim(email)) WHERE email <> lower(trim(email));
+
+CREATE UNIQUE INDEX IF NOT EXISTS rl_users_email_lower_key ON rl_users (lower(email));
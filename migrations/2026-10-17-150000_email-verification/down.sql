@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS email_verifications;
+
+ALTER TABLE rl_users DROP COLUMN IF EXISTS email_verified_at;
@@ -0,0 +1,15 @@
+-- Your SQL goes here
+ALTER TABLE rl_users ADD COLUMN IF NOT EXISTS email_verified_at timestamp;
+
+CREATE TABLE IF NOT EXISTS email_verifications
+(
+    id_email_verification uuid primary key,
+    id_user               uuid         not null,
+    email                 varchar(200) not null,
+    created_at            timestamp    not null,
+    expires_at            timestamp    not null,
+    used_at               timestamp,
+    CONSTRAINT fk_email_verifications_user foreign key (id_user) references rl_users (id_user)
+);
+
+CREATE INDEX IF NOT EXISTS email_verifications_user_idx ON email_verifications (id_user);
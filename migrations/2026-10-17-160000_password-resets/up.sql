@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE IF NOT EXISTS password_resets
+(
+    id_password_reset uuid primary key,
+    id_user           uuid        not null,
+    token_hash        varchar(64) not null unique,
+    created_at        timestamp   not null,
+    expires_at        timestamp   not null,
+    used_at           timestamp,
+    CONSTRAINT fk_password_resets_user FOREIGN KEY (id_user) references rl_users (id_user)
+);
+
+CREATE INDEX IF NOT EXISTS password_resets_user_idx ON password_resets (id_user);
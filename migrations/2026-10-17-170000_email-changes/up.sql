@@ -0,0 +1,17 @@
+-- Your SQL goes here
+CREATE TABLE IF NOT EXISTS email_changes
+(
+    id_email_change   uuid primary key,
+    id_user           uuid         not null,
+    old_email         varchar(200) not null,
+    new_email         varchar(200) not null,
+    token_hash        varchar(64)  not null unique,
+    cancel_token_hash varchar(64)  not null unique,
+    created_at        timestamp    not null,
+    expires_at        timestamp    not null,
+    confirmed_at      timestamp,
+    cancelled_at      timestamp,
+    CONSTRAINT fk_email_changes_user FOREIGN KEY (id_user) references rl_users (id_user)
+);
+
+CREATE INDEX IF NOT EXISTS email_changes_user_idx ON email_changes (id_user);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS recovery_codes;
+DROP TABLE IF EXISTS user_totp;
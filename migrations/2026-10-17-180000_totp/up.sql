@@ -0,0 +1,21 @@
+-- Your SQL goes here
+CREATE TABLE IF NOT EXISTS user_totp
+(
+    id_user        uuid primary key,
+    secret         varchar(64) not null,
+    created_at     timestamp   not null,
+    confirmed_at   timestamp,
+    last_used_step bigint,
+    CONSTRAINT fk_user_totp_user FOREIGN KEY (id_user) references rl_users (id_user)
+);
+
+CREATE TABLE IF NOT EXISTS recovery_codes
+(
+    id_recovery_code uuid primary key,
+    id_user          uuid        not null,
+    code_hash        varchar(64) not null,
+    used_at          timestamp,
+    CONSTRAINT fk_recovery_codes_user FOREIGN KEY (id_user) references rl_users (id_user)
+);
+
+CREATE INDEX IF NOT EXISTS recovery_codes_user_idx ON recovery_codes (id_user);
@@ -0,0 +1,28 @@
+-- Your SQL goes here
+CREATE TABLE IF NOT EXISTS webauthn_credentials
+(
+    id_webauthn_credential uuid primary key,
+    id_user                uuid          not null,
+    credential_id          varchar(1400) not null unique,
+    public_key             bytea         not null,
+    sign_count             bigint        not null,
+    label                  varchar(100),
+    created_at             timestamp     not null,
+    last_used_at           timestamp,
+    CONSTRAINT fk_webauthn_credentials_user FOREIGN KEY (id_user) references rl_users (id_user)
+);
+
+CREATE INDEX IF NOT EXISTS webauthn_credentials_user_idx ON webauthn_credentials (id_user);
+
+CREATE TABLE IF NOT EXISTS webauthn_challenges
+(
+    id_webauthn_challenge uuid primary key,
+    id_user               uuid,
+    challenge             varchar(64) not null,
+    ceremony              varchar(20) not null,
+    created_at            timestamp   not null,
+    expires_at            timestamp   not null,
+    CONSTRAINT fk_webauthn_challenges_user FOREIGN KEY (id_user) references rl_users (id_user)
+);
+
+CREATE INDEX IF NOT EXISTS webauthn_challenges_expires_at_idx ON webauthn_challenges (expires_at);
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+CREATE TABLE IF NOT EXISTS login_codes
+(
+    id_login_code   uuid primary key,
+    id_user         uuid        not null,
+    code_hash       varchar(64) not null,
+    created_at      timestamp   not null,
+    expires_at      timestamp   not null,
+    used_at         timestamp,
+    failed_attempts int         not null default 0,
+    CONSTRAINT fk_login_codes_user FOREIGN KEY (id_user) references rl_users (id_user)
+);
+
+CREATE INDEX IF NOT EXISTS login_codes_user_idx ON login_codes (id_user);
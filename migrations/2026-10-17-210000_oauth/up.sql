@@ -0,0 +1,29 @@
+-- Your SQL goes here
+CREATE TABLE IF NOT EXISTS oauth_clients
+(
+    id_oauth_client    uuid primary key,
+    client_id          varchar(100) not null unique,
+    client_secret_hash varchar(64),
+    name               varchar(100) not null,
+    redirect_uris      text[]       not null default '{}',
+    grant_types        text[]       not null default '{}',
+    scopes             text[]       not null default '{}',
+    first_party        boolean      not null default false,
+    created_at         timestamp    not null default now()
+);
+
+CREATE TABLE IF NOT EXISTS oauth_authorization_codes
+(
+    id_oauth_authorization_code uuid primary key,
+    code_hash                   varchar(64)  not null unique,
+    id_oauth_client             uuid         not null,
+    id_user                     uuid         not null,
+    redirect_uri                text         not null,
+    scope                       text         not null,
+    code_challenge              varchar(128) not null,
+    created_at                  timestamp    not null,
+    expires_at                  timestamp    not null,
+    used_at                     timestamp,
+    CONSTRAINT fk_oauth_authorization_codes_client FOREIGN KEY (id_oauth_client) references oauth_clients (id_oauth_client),
+    CONSTRAINT fk_oauth_authorization_codes_user FOREIGN KEY (id_user) references rl_users (id_user)
+);
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE oauth_authorization_codes ADD COLUMN IF NOT EXISTS nonce varchar(255);
@@ -1,24 +1,209 @@
 use std::env;
+use std::str::FromStr;
+use std::sync::Arc;
 
 use actix_web::dev::ServiceRequest;
-use actix_web::Error;
+use actix_web::{web, Error, HttpMessage};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
 use anyhow::anyhow;
-use jsonwebtoken::{Algorithm, decode, DecodingKey, encode, EncodingKey, Header, Validation};
+use chrono::NaiveDateTime;
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{decode, decode_header, encode, Header, Validation};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::model::RLUser;
 use crate::users::UserError;
+use crate::{db, keys, DbPool};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenKind {
+    Access,
+    Refresh,
+    /// Proves the password step of a login; only exchangeable for tokens together
+    /// with a second factor.
+    Mfa,
+}
+
+impl TokenKind {
+    pub fn audience(&self) -> &'static str {
+        match self {
+            TokenKind::Access => "RLApi",
+            TokenKind::Refresh => "RLAuth",
+            TokenKind::Mfa => "RLMfa",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TokenClaims {
     pub exp: i64,
+    /// Missing on tokens issued before it was added.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
     pub iss: String,
     pub sub: String,
+    pub aud: String,
+    pub token_kind: TokenKind,
     pub user_id: String,
     pub connection_id: String,
     pub roles: Vec<String>,
+    /// Set on tokens issued through the OAuth endpoints, named as in RFC 9068.
+    #[serde(rename = "client_id", default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+}
+
+impl TokenClaims {
+    pub fn user_uuid(&self) -> Result<Uuid, UserError> {
+        Uuid::from_str(&self.user_id).map_err(|e| UserError::from(anyhow!("{}", e)))
+    }
+
+    pub fn connection_uuid(&self) -> Result<Uuid, UserError> {
+        Uuid::from_str(&self.connection_id).map_err(|e| UserError::from(anyhow!("{}", e)))
+    }
+}
+
+pub const EMAIL_VERIFICATION_AUDIENCE: &str = "RLEmailVerification";
+
+/// Claims of the single-use links we send by email. `jti` is the id of the row
+/// that records whether the link was used, `aud` what it may be used for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailTokenClaims {
+    pub exp: i64,
+    pub iss: String,
+    pub aud: String,
+    pub jti: String,
+    pub user_id: String,
+    pub email: String,
+}
+
+impl EmailTokenClaims {
+    pub fn jti_uuid(&self) -> Result<Uuid, UserError> {
+        Uuid::from_str(&self.jti).map_err(|_| UserError::InvalidToken)
+    }
+
+    pub fn user_uuid(&self) -> Result<Uuid, UserError> {
+        Uuid::from_str(&self.user_id).map_err(|_| UserError::InvalidToken)
+    }
+}
+
+pub fn generate_email_token(
+    audience: &str,
+    jti: Uuid,
+    user_id: Uuid,
+    email: &str,
+    expires_at: NaiveDateTime,
+) -> anyhow::Result<String> {
+    sign(&EmailTokenClaims {
+        exp: expires_at.and_utc().timestamp(),
+        iss: "RLBackend".to_string(),
+        aud: audience.to_string(),
+        jti: jti.to_string(),
+        user_id: user_id.to_string(),
+        email: email.to_string(),
+    })
+}
+
+/// Expired, tampered and wrong-purpose links are all reported the same way.
+pub fn get_email_token_claims(token: &str, audience: &str) -> Result<EmailTokenClaims, UserError> {
+    verify(token, audience).map_err(|_| UserError::InvalidToken)
+}
+
+/// Claims about the user an OAuth client may read, limited by the granted scope.
+/// Shared by ID tokens and `/userinfo`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserInfo {
+    pub sub: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_verified: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nickname: Option<String>,
+}
+
+impl UserInfo {
+    pub fn new(user: &RLUser, scope: &str) -> Self {
+        let email = has_scope(scope, "email");
+        Self {
+            sub: user.id_user.to_string(),
+            email: email.then(|| user.email.clone()),
+            email_verified: email.then_some(user.email_verified_at.is_some()),
+            nickname: has_scope(scope, "profile").then(|| user.nickname.clone()),
+        }
+    }
+}
+
+/// Claims of an OpenID Connect ID token. Unlike in our other tokens, `iss` is the
+/// public URL of the discovery document and `aud` the client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub aud: String,
+    pub exp: i64,
+    pub iat: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(flatten)]
+    pub user_info: UserInfo,
+}
+
+pub fn oidc_issuer() -> String {
+    env::var("OIDC_ISSUER")
+        .expect("OIDC_ISSUER must be set.")
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// Clients verify ID tokens themselves, so they can only be issued while the
+/// active key is an RS256 or EdDSA key published in the JWK set.
+pub fn id_tokens_supported() -> bool {
+    keys::keyring().public_signing_key().is_some()
+}
+
+pub fn generate_id_token(
+    user: &RLUser,
+    client_id: &str,
+    scope: &str,
+    nonce: Option<&str>,
+) -> anyhow::Result<String> {
+    let keyring = keys::keyring();
+    let signing_key = keyring
+        .public_signing_key()
+        .ok_or_else(|| anyhow!("ID tokens need an RS256 or EdDSA signing key."))?;
+    sign_with(signing_key, &id_token_claims(user, client_id, scope, nonce))
+}
+
+fn id_token_claims(
+    user: &RLUser,
+    client_id: &str,
+    scope: &str,
+    nonce: Option<&str>,
+) -> IdTokenClaims {
+    let id_token_duration = env::var("ID_TOKEN_EXP_SEC")
+        .expect("ID_TOKEN_EXP_SEC must be set.")
+        .parse()
+        .expect("ID_TOKEN_EXP_SEC must be a number.");
+    let now = chrono::Utc::now();
+    IdTokenClaims {
+        iss: oidc_issuer(),
+        aud: client_id.to_string(),
+        exp: (now + chrono::Duration::seconds(id_token_duration)).timestamp(),
+        iat: now.timestamp(),
+        nonce: nonce.map(|nonce| nonce.to_string()),
+        user_info: UserInfo::new(user, scope),
+    }
+}
+
+/// Scopes are a space separated list, as in OAuth requests.
+pub fn has_scope(scope: &str, wanted: &str) -> bool {
+    scope.split_whitespace().any(|granted| granted == wanted)
 }
 
 pub fn generate_tokens(
@@ -26,7 +211,61 @@ pub fn generate_tokens(
     connection_id: Uuid,
     roles: Vec<String>,
 ) -> anyhow::Result<(String, String, i64)> {
-    let header = Header::new(Algorithm::HS256);
+    generate_token_pair(user_id, connection_id, roles, None, None)
+}
+
+/// Tokens a user granted to an OAuth client. They are refreshed through the
+/// client, never through `/users/token`.
+pub fn generate_oauth_tokens(
+    user_id: Uuid,
+    connection_id: Uuid,
+    roles: Vec<String>,
+    client_id: &str,
+    scope: &str,
+) -> anyhow::Result<(String, String, i64)> {
+    generate_token_pair(
+        user_id,
+        connection_id,
+        roles,
+        Some(client_id.to_string()),
+        Some(scope.to_string()),
+    )
+}
+
+/// A client acting on its own behalf has no user and no connection, so the token
+/// can't be refreshed and only lives as long as an access token.
+pub fn generate_client_credentials_token(
+    client_id: &str,
+    scope: &str,
+) -> anyhow::Result<(String, i64)> {
+    let access_duration = env::var("ACCESS_TOKEN_EXP_SEC")
+        .expect("ACCESS_TOKEN_EXP_SEC must be set.")
+        .parse()
+        .expect("ACCESS_TOKEN_EXP_SEC must be a number.");
+    let claims = TokenClaims {
+        sub: "RLClient".to_string(),
+        iat: Some(chrono::Utc::now().timestamp()),
+        iss: "RLBackend".to_string(),
+        aud: TokenKind::Access.audience().to_string(),
+        exp: (chrono::Utc::now() + chrono::Duration::seconds(access_duration)).timestamp(),
+        token_kind: TokenKind::Access,
+        user_id: Uuid::nil().to_string(),
+        connection_id: Uuid::nil().to_string(),
+        roles: vec![],
+        client_id: Some(client_id.to_string()),
+        scope: Some(scope.to_string()),
+    };
+
+    Ok((sign(&claims)?, access_duration))
+}
+
+fn generate_token_pair(
+    user_id: Uuid,
+    connection_id: Uuid,
+    roles: Vec<String>,
+    client_id: Option<String>,
+    scope: Option<String>,
+) -> anyhow::Result<(String, String, i64)> {
     let access_duration = env::var("ACCESS_TOKEN_EXP_SEC")
         .expect("ACCESS_TOKEN_EXP_SEC must be set.")
         .parse()
@@ -35,18 +274,18 @@ pub fn generate_tokens(
     let expiration_access = chrono::Utc::now() + duration;
     let claims = TokenClaims {
         sub: "RLClient".to_string(),
+        iat: Some(chrono::Utc::now().timestamp()),
         iss: "RLBackend".to_string(),
+        aud: TokenKind::Access.audience().to_string(),
         exp: expiration_access.timestamp(),
+        token_kind: TokenKind::Access,
         user_id: user_id.clone().to_string(),
         connection_id: connection_id.clone().to_string(),
         roles: roles.clone(),
+        client_id: client_id.clone(),
+        scope: scope.clone(),
     };
-    let access_token = encode(
-        &header,
-        &claims,
-        &EncodingKey::from_secret(get_secret().as_ref()),
-    )
-    .map_err(|e| anyhow!("{}", e))?;
+    let access_token = sign(&claims)?;
     let refresh_duration = env::var("REFRESH_TOKEN_EXP_DAY")
         .expect("REFRESH_TOKEN_EXP_DAY must be set.")
         .parse()
@@ -55,75 +294,428 @@ pub fn generate_tokens(
     let expiration = chrono::Utc::now() + duration;
     let claims = TokenClaims {
         sub: "RLClient".to_string(),
+        iat: Some(chrono::Utc::now().timestamp()),
         iss: "RLBackend".to_string(),
+        aud: TokenKind::Refresh.audience().to_string(),
         exp: expiration.timestamp(),
+        token_kind: TokenKind::Refresh,
         user_id: user_id.to_string(),
         connection_id: connection_id.to_string(),
         roles,
+        client_id,
+        scope,
     };
-    let refresh_token = encode(
-        &header,
-        &claims,
-        &EncodingKey::from_secret(get_secret().as_ref()),
-    )
-    .map_err(|e| anyhow!("{}", e))?;
+    let refresh_token = sign(&claims)?;
 
     let expire_in = expiration_access.timestamp() - chrono::Utc::now().timestamp();
 
     Ok((access_token, refresh_token, expire_in))
 }
 
-pub fn get_claims_and_validate(token: String) -> anyhow::Result<TokenClaims> {
-    let secret_key = get_secret();
+/// The challenge belongs to no connection yet and carries no roles.
+pub fn generate_mfa_token(user_id: Uuid) -> anyhow::Result<(String, i64)> {
+    let mfa_duration = env::var("MFA_TOKEN_EXP_SEC")
+        .expect("MFA_TOKEN_EXP_SEC must be set.")
+        .parse()
+        .expect("MFA_TOKEN_EXP_SEC must be a number.");
+    let claims = TokenClaims {
+        sub: "RLClient".to_string(),
+        iat: Some(chrono::Utc::now().timestamp()),
+        iss: "RLBackend".to_string(),
+        aud: TokenKind::Mfa.audience().to_string(),
+        exp: (chrono::Utc::now() + chrono::Duration::seconds(mfa_duration)).timestamp(),
+        token_kind: TokenKind::Mfa,
+        user_id: user_id.to_string(),
+        connection_id: Uuid::nil().to_string(),
+        roles: vec![],
+        client_id: None,
+        scope: None,
+    };
+
+    Ok((sign(&claims)?, mfa_duration))
+}
+
+pub fn get_claims_and_validate(token: String, kind: TokenKind) -> Result<TokenClaims, UserError> {
     let token = token.replace("Bearer ", "");
-    let claims = decode::<TokenClaims>(
-        &token,
-        &DecodingKey::from_secret(secret_key.as_ref()),
-        &Validation::default(),
-    )?;
-    Ok(claims.claims)
+    let claims: TokenClaims = verify(&token, kind.audience())?;
+
+    if claims.token_kind != kind {
+        return Err(UserError::WrongTokenKind);
+    }
+    Ok(claims)
+}
+
+fn sign<T: Serialize>(claims: &T) -> anyhow::Result<String> {
+    sign_with(keys::keyring().signing_key(), claims)
+}
+
+fn sign_with<T: Serialize>(signing_key: &keys::JwtKey, claims: &T) -> anyhow::Result<String> {
+    let mut header = Header::new(signing_key.algorithm);
+    header.kid = Some(signing_key.kid.clone());
+    encode(&header, claims, signing_key.encoding_key()?).map_err(|e| anyhow!("{}", e))
+}
+
+fn verify<T: DeserializeOwned>(token: &str, audience: &str) -> Result<T, UserError> {
+    let keyring = keys::keyring();
+    let header = decode_header(token).map_err(|e| anyhow!("{}", e))?;
+    let verification_key = keyring
+        .verification_key(header.kid.as_deref())
+        .ok_or_else(|| anyhow!("Unknown signing key."))?;
+    let mut validation = Validation::new(verification_key.algorithm);
+    validation.set_audience(&[audience]);
+    decode::<T>(token, &verification_key.decoding_key, &validation)
+        .map(|data| data.claims)
+        .map_err(|e| match e.kind() {
+            ErrorKind::InvalidAudience => UserError::WrongTokenKind,
+            _ => UserError::from(anyhow!("{}", e)),
+        })
+}
+
+/// Decides whether the connection a token was issued for is still live. Called
+/// from a blocking thread.
+pub trait ConnectionCheck: Send + Sync {
+    fn validate(&self, user_id: Uuid, connection_id: Uuid) -> Result<(), UserError>;
 }
 
-#[derive(Debug, Clone)]
+/// Looks the connection up in the database, and when `REQUIRE_VERIFIED_EMAIL` is
+/// set also that the user has verified their email.
+pub struct DbConnectionCheck {
+    pool: DbPool,
+    require_verified_email: bool,
+}
+
+impl DbConnectionCheck {
+    pub fn new(pool: DbPool) -> Self {
+        Self {
+            pool,
+            require_verified_email: require_verified_email(),
+        }
+    }
+}
+
+impl ConnectionCheck for DbConnectionCheck {
+    fn validate(&self, user_id: Uuid, connection_id: Uuid) -> Result<(), UserError> {
+        let mut conn = self
+            .pool
+            .get()
+            .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+        db::validate_connection(&mut conn, user_id, connection_id)?;
+        if self.require_verified_email {
+            db::validate_email_verified(&mut conn, user_id)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
 pub struct AuthValidator {
-    pub valid_role: String,
+    pub valid_role: Option<String>,
+    pub valid_scope: Option<String>,
+    connection_check: Arc<dyn ConnectionCheck>,
 }
 
 impl AuthValidator {
-    pub fn new(role: String) -> Self {
-        Self { valid_role: role }
+    pub fn new(role: String, connection_check: Arc<dyn ConnectionCheck>) -> Self {
+        Self {
+            valid_role: Some(role),
+            valid_scope: None,
+            connection_check,
+        }
     }
-    pub fn validator(
+
+    /// For endpoints serving OAuth clients, which look at the granted scope
+    /// instead of the roles.
+    pub fn scoped(scope: String, connection_check: Arc<dyn ConnectionCheck>) -> Self {
+        Self {
+            valid_role: None,
+            valid_scope: Some(scope),
+            connection_check,
+        }
+    }
+    pub async fn validator(
         &self,
         req: ServiceRequest,
         credentials: BearerAuth,
     ) -> Result<ServiceRequest, (Error, ServiceRequest)> {
-        let secret_key = get_secret();
-        let token = credentials.token().replace("Bearer ", "");
-        let claims = decode::<TokenClaims>(
-            &token,
-            &DecodingKey::from_secret(secret_key.as_ref()),
-            &Validation::default(),
-        );
-
-        match claims {
-            Ok(val) => {
-                if !val
-                    .claims
-                    .roles
-                    .iter()
-                    .any(|r| r.eq_ignore_ascii_case(&self.valid_role))
-                {
-                    return Err((Error::from(UserError::Forbidden), req));
-                }
-
-                Ok(req)
+        let claims = get_claims_and_validate(credentials.token().to_string(), TokenKind::Access);
+
+        let claims = match claims {
+            Ok(val) => val,
+            Err(UserError::WrongTokenKind) => {
+                return Err((Error::from(UserError::WrongTokenKind), req))
+            }
+            Err(_) => return Err((Error::from(UserError::Forbidden), req)),
+        };
+
+        if let Some(valid_role) = &self.valid_role {
+            if !claims
+                .roles
+                .iter()
+                .any(|r| r.eq_ignore_ascii_case(valid_role))
+            {
+                return Err((Error::from(UserError::Forbidden), req));
             }
-            Err(_) => Err((Error::from(UserError::Forbidden), req)),
         }
+        if let Some(valid_scope) = &self.valid_scope {
+            if !claims
+                .scope
+                .as_deref()
+                .is_some_and(|scope| has_scope(scope, valid_scope))
+            {
+                return Err((Error::from(UserError::Forbidden), req));
+            }
+        }
+
+        if let Err(e) = self.validate_active_connection(&claims).await {
+            return Err((Error::from(e), req));
+        }
+
+        req.extensions_mut().insert(claims);
+        Ok(req)
+    }
+
+    async fn validate_active_connection(&self, claims: &TokenClaims) -> Result<(), UserError> {
+        let user_id = claims.user_uuid().map_err(|_| UserError::Forbidden)?;
+        let connection_id = claims.connection_uuid().map_err(|_| UserError::Forbidden)?;
+        let connection_check = self.connection_check.clone();
+
+        web::block(move || connection_check.validate(user_id, connection_id)).await?
     }
 }
 
-fn get_secret() -> String {
-    env::var("SECRET").expect("SECRET must be set")
+fn require_verified_email() -> bool {
+    env::var("REQUIRE_VERIFIED_EMAIL")
+        .map(|value| {
+            value
+                .parse()
+                .expect("REQUIRE_VERIFIED_EMAIL must be true or false.")
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::{BoxBody, EitherBody};
+    use actix_web::dev::ServiceResponse;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+    use actix_web_httpauth::middleware::HttpAuthentication;
+
+    use super::*;
+
+    fn set_test_env() {
+        env::set_var("SECRET", "test-secret");
+        env::set_var("ACCESS_TOKEN_EXP_SEC", "180");
+        env::set_var("REFRESH_TOKEN_EXP_DAY", "90");
+    }
+
+    fn test_tokens() -> (String, String) {
+        set_test_env();
+        let (access_token, refresh_token, _) =
+            generate_tokens(Uuid::new_v4(), Uuid::new_v4(), vec!["USER".to_string()]).unwrap();
+        (access_token, refresh_token)
+    }
+
+    #[test]
+    fn accepts_each_token_for_its_own_kind() {
+        let (access_token, refresh_token) = test_tokens();
+
+        let access = get_claims_and_validate(access_token, TokenKind::Access).unwrap();
+        let refresh = get_claims_and_validate(refresh_token, TokenKind::Refresh).unwrap();
+
+        assert_eq!(access.token_kind, TokenKind::Access);
+        assert_eq!(refresh.token_kind, TokenKind::Refresh);
+    }
+
+    #[test]
+    fn rejects_access_token_as_refresh_token() {
+        let (access_token, _) = test_tokens();
+
+        let result = get_claims_and_validate(access_token, TokenKind::Refresh);
+
+        assert!(matches!(result, Err(UserError::WrongTokenKind)));
+    }
+
+    #[test]
+    fn rejects_refresh_token_as_access_token() {
+        let (_, refresh_token) = test_tokens();
+
+        let result = get_claims_and_validate(refresh_token, TokenKind::Access);
+
+        assert!(matches!(result, Err(UserError::WrongTokenKind)));
+    }
+
+    #[test]
+    fn rejects_token_with_mismatched_kind_claim() {
+        set_test_env();
+        let claims = TokenClaims {
+            exp: (chrono::Utc::now() + chrono::Duration::seconds(60)).timestamp(),
+            iat: None,
+            iss: "RLBackend".to_string(),
+            sub: "RLClient".to_string(),
+            aud: TokenKind::Access.audience().to_string(),
+            token_kind: TokenKind::Refresh,
+            user_id: Uuid::new_v4().to_string(),
+            connection_id: Uuid::new_v4().to_string(),
+            roles: vec!["USER".to_string()],
+            client_id: None,
+            scope: None,
+        };
+        let keyring = keys::keyring();
+        let signing_key = keyring.signing_key();
+        let token = encode(
+            &Header::new(signing_key.algorithm),
+            &claims,
+            signing_key.encoding_key().unwrap(),
+        )
+        .unwrap();
+
+        let result = get_claims_and_validate(token, TokenKind::Access);
+
+        assert!(matches!(result, Err(UserError::WrongTokenKind)));
+    }
+
+    #[test]
+    fn email_tokens_are_only_accepted_for_their_purpose() {
+        set_test_env();
+        let jti = Uuid::new_v4();
+        let expires_at = (chrono::Utc::now() + chrono::Duration::hours(1)).naive_utc();
+        let token = generate_email_token(
+            EMAIL_VERIFICATION_AUDIENCE,
+            jti,
+            Uuid::new_v4(),
+            "ana@rl.com",
+            expires_at,
+        )
+        .unwrap();
+
+        let claims = get_email_token_claims(&token, EMAIL_VERIFICATION_AUDIENCE).unwrap();
+        assert_eq!(claims.jti_uuid().unwrap(), jti);
+        assert_eq!(claims.email, "ana@rl.com");
+        assert!(matches!(
+            get_email_token_claims(&token, "RLPasswordReset"),
+            Err(UserError::InvalidToken)
+        ));
+        assert!(get_claims_and_validate(token, TokenKind::Access).is_err());
+    }
+
+    #[test]
+    fn id_token_only_carries_claims_for_the_granted_scope() {
+        set_test_env();
+        env::set_var("OIDC_ISSUER", "https://auth.rl.com/");
+        env::set_var("ID_TOKEN_EXP_SEC", "300");
+        let user = RLUser {
+            id_user: Uuid::new_v4(),
+            email: "ana@rl.com".to_string(),
+            nickname: "Ana".to_string(),
+            password: String::new(),
+            id_role: 1,
+            email_verified_at: None,
+        };
+
+        let claims = id_token_claims(&user, "movies-spa", "openid email", Some("n-0S6"));
+
+        assert_eq!(claims.iss, "https://auth.rl.com");
+        assert_eq!(claims.nonce.as_deref(), Some("n-0S6"));
+        assert_eq!(claims.user_info.sub, user.id_user.to_string());
+        assert_eq!(claims.user_info.email.as_deref(), Some("ana@rl.com"));
+        assert_eq!(claims.user_info.email_verified, Some(false));
+        assert_eq!(claims.user_info.nickname, None);
+    }
+
+    #[test]
+    fn refuses_to_sign_id_tokens_with_the_shared_secret() {
+        set_test_env();
+        env::set_var("OIDC_ISSUER", "https://auth.rl.com/");
+        env::set_var("ID_TOKEN_EXP_SEC", "300");
+        let user = RLUser {
+            id_user: Uuid::new_v4(),
+            email: "ana@rl.com".to_string(),
+            nickname: "Ana".to_string(),
+            password: String::new(),
+            id_role: 1,
+            email_verified_at: None,
+        };
+
+        assert!(!id_tokens_supported());
+        assert!(generate_id_token(&user, "movies-spa", "openid", None).is_err());
+    }
+
+    #[test]
+    fn mfa_token_is_not_accepted_as_access_token() {
+        set_test_env();
+        env::set_var("MFA_TOKEN_EXP_SEC", "300");
+        let (mfa_token, _) = generate_mfa_token(Uuid::new_v4()).unwrap();
+
+        assert!(get_claims_and_validate(mfa_token.clone(), TokenKind::Mfa).is_ok());
+        assert!(matches!(
+            get_claims_and_validate(mfa_token, TokenKind::Access),
+            Err(UserError::WrongTokenKind)
+        ));
+    }
+
+    /// Stands in for the connections table: only `live` is still logged in.
+    struct LiveConnection(Uuid);
+
+    impl ConnectionCheck for LiveConnection {
+        fn validate(&self, _: Uuid, connection_id: Uuid) -> Result<(), UserError> {
+            if connection_id == self.0 {
+                Ok(())
+            } else {
+                Err(UserError::ExpiredToken)
+            }
+        }
+    }
+
+    async fn call_with_bearer(
+        connection_check: Arc<dyn ConnectionCheck>,
+        token: &str,
+    ) -> ServiceResponse<EitherBody<BoxBody>> {
+        let auth = HttpAuthentication::bearer(move |req, credentials| {
+            let auth_validator = AuthValidator::new("USER".to_string(), connection_check.clone());
+            async move { auth_validator.validator(req, credentials).await }
+        });
+        let app = init_service(
+            App::new()
+                .wrap(auth)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = TestRequest::get()
+            .uri("/")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        call_service(&app, req).await
+    }
+
+    #[actix_web::test]
+    async fn validator_rejects_refresh_token_as_bearer() {
+        set_test_env();
+        let connection_id = Uuid::new_v4();
+        let (access_token, refresh_token, _) =
+            generate_tokens(Uuid::new_v4(), connection_id, vec!["USER".to_string()]).unwrap();
+        let connection_check = Arc::new(LiveConnection(connection_id));
+
+        let res = call_with_bearer(connection_check.clone(), &refresh_token).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let body: crate::users::UserErrorResponse = read_body_json(res).await;
+        assert_eq!(body.internal_code, "WTK-00403");
+
+        let res = call_with_bearer(connection_check, &access_token).await;
+        assert!(res.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn validator_rejects_token_of_ended_connection() {
+        set_test_env();
+        let (access_token, _, _) =
+            generate_tokens(Uuid::new_v4(), Uuid::new_v4(), vec!["USER".to_string()]).unwrap();
+
+        let res = call_with_bearer(Arc::new(LiveConnection(Uuid::new_v4())), &access_token).await;
+
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let body: crate::users::UserErrorResponse = read_body_json(res).await;
+        assert_eq!(body.internal_code, "ET-00403");
+    }
 }
@@ -0,0 +1,125 @@
+use std::env;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+
+use actix_web::http::header::HeaderMap;
+use actix_web::HttpRequest;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Reverse proxies whose `X-Forwarded-For` we believe, from `TRUSTED_PROXIES`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrustedProxies {
+    pub addresses: Vec<IpAddr>,
+}
+
+impl TrustedProxies {
+    /// Reads a comma separated list of IP addresses; unset means no proxy is trusted.
+    pub fn from_env() -> Self {
+        let addresses = env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+            .map(|address| {
+                address
+                    .parse()
+                    .unwrap_or_else(|_| panic!("TRUSTED_PROXIES has an invalid IP: {}", address))
+            })
+            .collect();
+        Self { addresses }
+    }
+
+    /// The peer is who we talk to, so it is the client unless it is one of our
+    /// proxies. Then `X-Forwarded-For` is walked from the right, since only the
+    /// entries our proxies appended can be believed, up to the first address that
+    /// isn't a proxy. Anything a client sends itself lands left of that.
+    pub fn resolve(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let peer = peer?;
+        if !self.addresses.contains(&peer) {
+            return Some(peer);
+        }
+
+        let forwarded = headers
+            .get_all(X_FORWARDED_FOR)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|address| address.trim().parse::<IpAddr>().ok())
+            .collect::<Vec<_>>();
+        let mut client = peer;
+        for address in forwarded.into_iter().rev() {
+            let Some(address) = address else {
+                break;
+            };
+            client = address;
+            if !self.addresses.contains(&address) {
+                break;
+            }
+        }
+        Some(client)
+    }
+}
+
+pub fn trusted_proxies() -> &'static TrustedProxies {
+    static TRUSTED_PROXIES: OnceLock<TrustedProxies> = OnceLock::new();
+    TRUSTED_PROXIES.get_or_init(TrustedProxies::from_env)
+}
+
+/// The address lockouts, rate limits and session records are keyed on.
+pub fn client_ip(req: &HttpRequest) -> Option<String> {
+    trusted_proxies()
+        .resolve(req.peer_addr().map(|addr| addr.ip()), req.headers())
+        .map(|ip| ip.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn headers(forwarded_for: &str) -> HeaderMap {
+        TestRequest::default()
+            .insert_header((X_FORWARDED_FOR, forwarded_for))
+            .to_http_request()
+            .headers()
+            .clone()
+    }
+
+    fn ip(address: &str) -> Option<IpAddr> {
+        Some(address.parse().unwrap())
+    }
+
+    #[test]
+    fn ignores_forwarded_for_from_untrusted_peers() {
+        let proxies = TrustedProxies::default();
+
+        assert_eq!(
+            proxies.resolve(ip("203.0.113.7"), &headers("198.51.100.1")),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn takes_the_address_our_proxies_saw() {
+        let proxies = TrustedProxies {
+            addresses: vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()],
+        };
+
+        assert_eq!(
+            proxies.resolve(
+                ip("10.0.0.1"),
+                &headers("198.51.100.1, 203.0.113.7, 10.0.0.2")
+            ),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            proxies.resolve(ip("10.0.0.1"), &headers("garbage, 203.0.113.7")),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            proxies.resolve(ip("10.0.0.1"), &HeaderMap::new()),
+            ip("10.0.0.1")
+        );
+    }
+}
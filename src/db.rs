@@ -1,89 +1,397 @@
+use std::env;
 use std::str::FromStr;
 
 use anyhow::anyhow;
-use bcrypt::{hash, verify, DEFAULT_COST};
-use chrono::{Local, NaiveDateTime};
+use chrono::{DateTime, Local, NaiveDateTime};
+use diesel::result::DatabaseErrorKind;
+use diesel::sql_types::Text;
 use diesel::{
-    BoolExpressionMethods, Connection, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl,
-    SelectableHelper,
+    define_sql_function, BoolExpressionMethods, Connection, ExpressionMethods, OptionalExtension,
+    PgConnection, QueryDsl, RunQueryDsl, SelectableHelper,
 };
 use uuid::Uuid;
 
-use crate::model::{ConnectionModel, RLRole, RLUser};
+use crate::lockout::AttemptKey;
+use crate::model::{
+    ConnectionModel, EmailChange, EmailVerification, LoginAttempt, LoginCode,
+    OAuthAuthorizationCode, OAuthClient, PasswordReset, RLRole, RLUser, RecoveryCode,
+    SecurityEvent, UserTotp, WebauthnChallenge, WebauthnCredential,
+};
+use crate::oauth::AuthorizationRequest;
+use crate::one_time_token::{generate_token, hash_code};
+use crate::password::{check_password, hashing};
 use crate::schema::rl_users::dsl::rl_users;
-use crate::users::{NewUser, UserError};
+use crate::users::{normalize_email, ClientInfo, NewUser, UserError};
+use crate::webauthn::RegisteredCredential;
+
+define_sql_function!(fn lower(x: Text) -> Text);
 
 pub fn save_new_user(conn: &mut PgConnection, new_user: NewUser) -> Result<Uuid, UserError> {
     use crate::schema::rl_users::dsl::*;
 
-    let hashed_password = hash(new_user.password, DEFAULT_COST)
-        .map_err(|e| anyhow!("Failed to hash password: {}", e))?;
-
-    let user = rl_users
-        .filter(email.eq(new_user.email.clone()))
-        .limit(1)
-        .load::<RLUser>(conn)
-        .map_err(|e| anyhow!("{}", e))?;
-
-    if !user.is_empty() {
-        return Err(UserError::EmailNotAvailable);
-    }
+    let hashed_password = hashing().hash(&new_user.password)?;
 
     let new_user = RLUser {
         id_user: Uuid::new_v4(),
-        email: new_user.email.clone(),
+        email: normalize_email(&new_user.email),
         nickname: new_user.nickname.clone(),
         password: hashed_password,
         id_role: 1,
+        email_verified_at: None,
     };
 
     let new_user_id = new_user.id_user;
     diesel::insert_into(rl_users)
         .values(new_user)
         .execute(conn)
-        .map_err(|e| anyhow!("{}", e))?;
+        .map_err(email_taken)?;
 
     Ok(new_user_id)
 }
-pub fn generate_new_connection(conn: &mut PgConnection, id: Uuid) -> Result<Uuid, UserError> {
+
+/// The unique index on lower(email) is the only race-free availability check.
+fn email_taken(e: diesel::result::Error) -> UserError {
+    match e {
+        diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+            UserError::EmailNotAvailable
+        }
+        e => UserError::from(e),
+    }
+}
+pub fn generate_new_connection(
+    conn: &mut PgConnection,
+    id: Uuid,
+    client: ClientInfo,
+) -> Result<Uuid, UserError> {
     use crate::schema::connections::dsl::*;
     use crate::schema::rl_users::dsl::id_user as user_id_user;
 
-    let user = rl_users
-        .filter(user_id_user.eq(id))
-        .select(RLUser::as_select())
-        .get_result(conn)
-        .map_err(|_| UserError::UserNotFound)?;
+    let max_sessions: i64 = env::var("MAX_SESSIONS_PER_USER")
+        .expect("MAX_SESSIONS_PER_USER must be set.")
+        .parse()
+        .expect("MAX_SESSIONS_PER_USER must be a number.");
 
-    let timestamp = Local::now();
+    let timestamp = now();
+    let new_connection_id = Uuid::new_v4();
     let new_connection = ConnectionModel {
-        id_connection: Uuid::new_v4(),
-        id_user: user.id_user,
-        connect_at: Some(NaiveDateTime::from_timestamp_opt(timestamp.timestamp(), 0).unwrap()),
+        id_connection: new_connection_id,
+        id_user: id,
+        connect_at: Some(timestamp),
         ended_at: None,
+        id_session: new_connection_id,
+        user_agent: client.user_agent,
+        ip_address: client.ip_address,
+        device_label: client.device_label,
+        refreshed_at: None,
+        rotated_at: None,
     };
 
-    let connection_id = new_connection.id_connection;
-
-    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+    conn.transaction::<_, UserError, _>(|conn| {
+        // Concurrent logins of the same user queue up here, so each one sees the
+        // sessions the others created before counting them.
+        rl_users
+            .filter(user_id_user.eq(id))
+            .select(user_id_user)
+            .for_update()
+            .first::<Uuid>(conn)
+            .optional()
+            .map_err(|e| anyhow!("{}", e))?
+            .ok_or(UserError::UserNotFound)?;
         let active_connections = connections
             .filter(id_user.eq(id).and(ended_at.is_null()))
-            .load::<ConnectionModel>(conn)?;
-        for con in active_connections {
+            .order(connect_at.asc())
+            .select(ConnectionModel::as_select())
+            .load(conn)
+            .map_err(|e| anyhow!("{}", e))?;
+        for con in connections_to_evict(&active_connections, max_sessions) {
             diesel::update(connections.filter(id_connection.eq(con.id_connection)))
-                .set(ended_at.eq(Some(
-                    NaiveDateTime::from_timestamp_opt(timestamp.timestamp(), 0).unwrap(),
-                )))
-                .execute(conn)?;
+                .set(ended_at.eq(Some(timestamp)))
+                .execute(conn)
+                .map_err(|e| anyhow!("{}", e))?;
         }
         diesel::insert_into(connections)
             .values(new_connection)
-            .execute(conn)?;
+            .execute(conn)
+            .map_err(|e| anyhow!("{}", e))?;
         Ok(())
-    })
+    })?;
+
+    Ok(new_connection_id)
+}
+
+/// The oldest of the active connections, oldest first, that have to end so a new
+/// one fits under the cap.
+fn connections_to_evict(
+    active_connections: &[ConnectionModel],
+    max_sessions: i64,
+) -> &[ConnectionModel] {
+    let exceeding = (active_connections.len() as i64 - max_sessions + 1).max(0) as usize;
+    &active_connections[..exceeding.min(active_connections.len())]
+}
+
+pub const REFRESH_TOKEN_REUSE_EVENT: &str = "REFRESH_TOKEN_REUSE";
+
+/// The rows refresh token rotation reads and writes. Implemented by the database
+/// connection, and by an in-memory table in tests.
+pub trait SessionStore {
+    fn in_transaction<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, UserError>,
+    ) -> Result<T, UserError>;
+    /// The connection, locked until the end of the transaction.
+    fn lock_connection(
+        &mut self,
+        user_id: Uuid,
+        connection_id: Uuid,
+    ) -> Result<Option<ConnectionModel>, UserError>;
+    fn end_session(&mut self, session_id: Uuid, timestamp: NaiveDateTime) -> Result<(), UserError>;
+    /// Ends the connection as rotated, or returns false if it had already ended.
+    fn end_rotated_connection(
+        &mut self,
+        connection_id: Uuid,
+        timestamp: NaiveDateTime,
+    ) -> Result<bool, UserError>;
+    fn insert_connection(&mut self, connection: ConnectionModel) -> Result<(), UserError>;
+    fn save_security_event(
+        &mut self,
+        user_id: Uuid,
+        event: &str,
+        session_id: Option<Uuid>,
+        client: ClientInfo,
+    ) -> Result<(), UserError>;
+}
+
+impl SessionStore for PgConnection {
+    fn in_transaction<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, UserError>,
+    ) -> Result<T, UserError> {
+        self.transaction(f)
+    }
+
+    fn lock_connection(
+        &mut self,
+        user_id: Uuid,
+        connection_id: Uuid,
+    ) -> Result<Option<ConnectionModel>, UserError> {
+        use crate::schema::connections::dsl::*;
+
+        Ok(connections
+            .filter(id_user.eq(user_id).and(id_connection.eq(connection_id)))
+            .select(ConnectionModel::as_select())
+            .for_update()
+            .first(self)
+            .optional()?)
+    }
+
+    fn end_session(&mut self, session_id: Uuid, timestamp: NaiveDateTime) -> Result<(), UserError> {
+        use crate::schema::connections::dsl::*;
+
+        diesel::update(connections.filter(id_session.eq(session_id).and(ended_at.is_null())))
+            .set(ended_at.eq(Some(timestamp)))
+            .execute(self)?;
+        Ok(())
+    }
+
+    fn end_rotated_connection(
+        &mut self,
+        connection_id: Uuid,
+        timestamp: NaiveDateTime,
+    ) -> Result<bool, UserError> {
+        use crate::schema::connections::dsl::*;
+
+        let ended = diesel::update(
+            connections.filter(id_connection.eq(connection_id).and(ended_at.is_null())),
+        )
+        .set((ended_at.eq(Some(timestamp)), rotated_at.eq(Some(timestamp))))
+        .execute(self)?;
+        Ok(ended > 0)
+    }
+
+    fn insert_connection(&mut self, connection: ConnectionModel) -> Result<(), UserError> {
+        use crate::schema::connections::dsl::*;
+
+        diesel::insert_into(connections)
+            .values(connection)
+            .execute(self)?;
+        Ok(())
+    }
+
+    fn save_security_event(
+        &mut self,
+        user_id: Uuid,
+        event: &str,
+        session_id: Option<Uuid>,
+        client: ClientInfo,
+    ) -> Result<(), UserError> {
+        save_security_event(self, user_id, event, session_id, client)
+    }
+}
+
+pub fn rotate_connection(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    connection_id: Uuid,
+    client: ClientInfo,
+) -> Result<Uuid, UserError> {
+    rotate_session(conn, user_id, connection_id, client)
+}
+
+/// Refreshing ends the connection behind the token and continues its session in a
+/// new one. Presenting an already rotated token again means it was stolen, so the
+/// whole session is ended and the reuse recorded, which must commit even though
+/// the refresh fails.
+fn rotate_session<S: SessionStore>(
+    store: &mut S,
+    user_id: Uuid,
+    connection_id: Uuid,
+    client: ClientInfo,
+) -> Result<Uuid, UserError> {
+    let timestamp = now();
+    let new_connection_id = Uuid::new_v4();
+
+    let rotated = store.in_transaction(|store| {
+        let current = store
+            .lock_connection(user_id, connection_id)?
+            .ok_or(UserError::ExpiredToken)?;
+
+        if current.rotated_at.is_some() {
+            log::warn!(
+                "Refresh token reuse detected for session {}, revoking it.",
+                current.id_session
+            );
+            store.end_session(current.id_session, timestamp)?;
+            store.save_security_event(
+                user_id,
+                REFRESH_TOKEN_REUSE_EVENT,
+                Some(current.id_session),
+                client,
+            )?;
+            return Ok(false);
+        }
+
+        if !store.end_rotated_connection(connection_id, timestamp)? {
+            return Err(UserError::ExpiredToken);
+        }
+
+        store.insert_connection(ConnectionModel {
+            id_connection: new_connection_id,
+            id_user: user_id,
+            connect_at: current.connect_at,
+            ended_at: None,
+            id_session: current.id_session,
+            user_agent: client.user_agent.or(current.user_agent),
+            ip_address: client.ip_address.or(current.ip_address),
+            device_label: client.device_label.or(current.device_label),
+            refreshed_at: Some(timestamp),
+            rotated_at: None,
+        })?;
+        Ok(true)
+    })?;
+
+    if !rotated {
+        return Err(UserError::RefreshTokenReused);
+    }
+    Ok(new_connection_id)
+}
+
+pub fn save_security_event(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    event: &str,
+    session_id: Option<Uuid>,
+    client: ClientInfo,
+) -> Result<(), UserError> {
+    use crate::schema::security_events::dsl::*;
+
+    diesel::insert_into(security_events)
+        .values(SecurityEvent {
+            id_security_event: Uuid::new_v4(),
+            id_user: user_id,
+            event_type: event.to_string(),
+            id_session: session_id,
+            ip_address: client.ip_address,
+            user_agent: client.user_agent,
+            created_at: Some(now()),
+        })
+        .execute(conn)
+        .map_err(|e| anyhow!("{}", e))?;
+
+    Ok(())
+}
+
+pub fn end_connection(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    connection_id: Uuid,
+) -> Result<(), UserError> {
+    use crate::schema::connections::dsl::*;
+
+    diesel::update(
+        connections.filter(
+            id_user
+                .eq(user_id)
+                .and(id_connection.eq(connection_id))
+                .and(ended_at.is_null()),
+        ),
+    )
+    .set(ended_at.eq(Some(now())))
+    .execute(conn)
     .map_err(|e| anyhow!("{}", e))?;
 
-    Ok(connection_id)
+    Ok(())
+}
+
+pub fn end_all_connections(conn: &mut PgConnection, user_id: Uuid) -> Result<(), UserError> {
+    use crate::schema::connections::dsl::*;
+
+    diesel::update(connections.filter(id_user.eq(user_id).and(ended_at.is_null())))
+        .set(ended_at.eq(Some(now())))
+        .execute(conn)
+        .map_err(|e| anyhow!("{}", e))?;
+
+    Ok(())
+}
+
+pub fn get_active_connections(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<Vec<ConnectionModel>, UserError> {
+    use crate::schema::connections::dsl::*;
+
+    let active_connections = connections
+        .filter(id_user.eq(user_id).and(ended_at.is_null()))
+        .order(connect_at.desc())
+        .select(ConnectionModel::as_select())
+        .load(conn)
+        .map_err(|e| anyhow!("{}", e))?;
+
+    Ok(active_connections)
+}
+
+pub fn end_session(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    session_id: Uuid,
+) -> Result<(), UserError> {
+    use crate::schema::connections::dsl::*;
+
+    let ended = diesel::update(
+        connections.filter(
+            id_user
+                .eq(user_id)
+                .and(id_session.eq(session_id))
+                .and(ended_at.is_null()),
+        ),
+    )
+    .set(ended_at.eq(Some(now())))
+    .execute(conn)
+    .map_err(|e| anyhow!("{}", e))?;
+
+    if ended == 0 {
+        return Err(UserError::SessionNotFound);
+    }
+    Ok(())
 }
 
 pub fn login(
@@ -94,15 +402,33 @@ pub fn login(
     use crate::schema::rl_role::dsl::id_role as role_id_role;
     use crate::schema::rl_role::dsl::rl_role;
     use crate::schema::rl_users::dsl::*;
-    let hashed_password = hash(password_login, DEFAULT_COST)
-        .map_err(|e| anyhow!("Failed to hash password: {}", e))?;
     let user = rl_users
-        .filter(email.eq(email_login))
+        .filter(lower(email).eq(normalize_email(&email_login)))
         .select(RLUser::as_select())
         .first(conn)
+        .optional()
         .map_err(|e| anyhow!("{}", e))?;
 
-    verify(user.password.clone(), &hashed_password).map_err(|_| UserError::InvalidCredentials)?;
+    let password_hashing = hashing();
+    check_password(
+        password_hashing,
+        &password_login,
+        user.as_ref().map(|u| u.password.as_str()),
+    )?;
+    let mut user = user.ok_or(UserError::InvalidCredentials)?;
+
+    if password_hashing.needs_rehash(&user.password) {
+        match password_hashing.hash(&password_login) {
+            Ok(new_hash) => {
+                diesel::update(rl_users.filter(id_user.eq(user.id_user)))
+                    .set(password.eq(&new_hash))
+                    .execute(conn)
+                    .map_err(|e| anyhow!("{}", e))?;
+                user.password = new_hash;
+            }
+            Err(e) => log::warn!("Couldn't rehash password for {}: {}", user.id_user, e),
+        }
+    }
 
     let role = rl_role
         .filter(role_id_role.eq(user.id_role))
@@ -113,6 +439,74 @@ pub fn login(
     Ok((user, role))
 }
 
+pub fn check_login_lock(conn: &mut PgConnection, keys: &[AttemptKey]) -> Result<(), UserError> {
+    use crate::schema::login_attempts::dsl::*;
+
+    let timestamp = now();
+    let locked = login_attempts
+        .filter(attempt_key.eq_any(keys.iter().map(|k| k.key.clone())))
+        .filter(locked_until.gt(timestamp))
+        .select(locked_until)
+        .load::<Option<NaiveDateTime>>(conn)
+        .map_err(|e| anyhow!("{}", e))?;
+
+    match locked.into_iter().flatten().max() {
+        Some(until) => Err(UserError::AccountLocked {
+            retry_after: (until - timestamp).num_seconds().max(1),
+        }),
+        None => Ok(()),
+    }
+}
+
+pub fn record_failed_login(conn: &mut PgConnection, keys: &[AttemptKey]) -> Result<(), UserError> {
+    use crate::schema::login_attempts::dsl::*;
+
+    let timestamp = now();
+    conn.transaction::<_, UserError, _>(|conn| {
+        for key in keys {
+            let current = login_attempts
+                .filter(attempt_key.eq(&key.key))
+                .select(LoginAttempt::as_select())
+                .for_update()
+                .first(conn)
+                .optional()?;
+            let count = match current {
+                Some(LoginAttempt {
+                    failed_count: count,
+                    last_failed_at: Some(last),
+                    ..
+                }) if (timestamp - last).num_seconds() < key.policy.reset_after_seconds => {
+                    count + 1
+                }
+                _ => 1,
+            };
+            let attempt = LoginAttempt {
+                attempt_key: key.key.clone(),
+                failed_count: count,
+                last_failed_at: Some(timestamp),
+                locked_until: Some(timestamp + key.policy.delay_after(count)),
+            };
+            diesel::insert_into(login_attempts)
+                .values(&attempt)
+                .on_conflict(attempt_key)
+                .do_update()
+                .set(&attempt)
+                .execute(conn)?;
+        }
+        Ok(())
+    })
+}
+
+pub fn clear_failed_logins(conn: &mut PgConnection, key: &AttemptKey) -> Result<(), UserError> {
+    use crate::schema::login_attempts::dsl::*;
+
+    diesel::delete(login_attempts.filter(attempt_key.eq(&key.key)))
+        .execute(conn)
+        .map_err(|e| anyhow!("{}", e))?;
+
+    Ok(())
+}
+
 pub fn get_role_by_user_id(conn: &mut PgConnection, user_id: String) -> Result<RLRole, UserError> {
     use crate::schema::rl_role::dsl::id_role;
     use crate::schema::rl_role::dsl::rl_role;
@@ -144,7 +538,10 @@ pub fn validate_connection(
         .first(conn)
         .map_err(|e| {
             log::info!("{}", e);
-            anyhow!("{}", e)
+            match e {
+                diesel::result::Error::NotFound => UserError::ExpiredToken,
+                e => UserError::from(anyhow!("{}", e)),
+            }
         })?;
 
     connection
@@ -154,3 +551,1072 @@ pub fn validate_connection(
 
     Ok(())
 }
+
+pub fn validate_email_verified(conn: &mut PgConnection, user_id: Uuid) -> Result<(), UserError> {
+    use crate::schema::rl_users::dsl::*;
+
+    let verified_at = rl_users
+        .filter(id_user.eq(user_id))
+        .select(email_verified_at)
+        .first::<Option<NaiveDateTime>>(conn)
+        .optional()
+        .map_err(|e| anyhow!("{}", e))?
+        .ok_or(UserError::UserNotFound)?;
+
+    verified_at.map(|_| ()).ok_or(UserError::EmailNotVerified)
+}
+
+pub fn get_user_by_email(
+    conn: &mut PgConnection,
+    user_email: &str,
+) -> Result<Option<RLUser>, UserError> {
+    use crate::schema::rl_users::dsl::*;
+
+    let user = rl_users
+        .filter(lower(email).eq(normalize_email(user_email)))
+        .select(RLUser::as_select())
+        .first(conn)
+        .optional()
+        .map_err(|e| anyhow!("{}", e))?;
+
+    Ok(user)
+}
+
+pub fn create_email_verification(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    user_email: &str,
+) -> Result<EmailVerification, UserError> {
+    use crate::schema::email_verifications::dsl::*;
+
+    let expiration_hours: i64 = env::var("EMAIL_VERIFICATION_EXP_HOUR")
+        .expect("EMAIL_VERIFICATION_EXP_HOUR must be set.")
+        .parse()
+        .expect("EMAIL_VERIFICATION_EXP_HOUR must be a number.");
+
+    let timestamp = now();
+    let verification = EmailVerification {
+        id_email_verification: Uuid::new_v4(),
+        id_user: user_id,
+        email: user_email.to_string(),
+        created_at: timestamp,
+        expires_at: timestamp + chrono::Duration::hours(expiration_hours),
+        used_at: None,
+    };
+    diesel::insert_into(email_verifications)
+        .values(&verification)
+        .execute(conn)
+        .map_err(|e| anyhow!("{}", e))?;
+
+    Ok(verification)
+}
+
+/// Uses up the verification and marks the address as verified, as long as it is
+/// still the user's current email.
+pub fn verify_email(
+    conn: &mut PgConnection,
+    verification_id: Uuid,
+    user_id: Uuid,
+    verified_email: &str,
+) -> Result<(), UserError> {
+    use crate::schema::email_verifications::dsl::*;
+    use crate::schema::rl_users::dsl::{
+        email as user_email, email_verified_at, id_user as user_id_user,
+    };
+
+    conn.transaction::<_, UserError, _>(|conn| {
+        let timestamp = now();
+        let used = diesel::update(
+            email_verifications.filter(
+                id_email_verification
+                    .eq(verification_id)
+                    .and(id_user.eq(user_id))
+                    .and(used_at.is_null())
+                    .and(expires_at.gt(timestamp)),
+            ),
+        )
+        .set(used_at.eq(Some(timestamp)))
+        .execute(conn)?;
+        if used == 0 {
+            return Err(UserError::InvalidToken);
+        }
+
+        let verified = diesel::update(
+            rl_users.filter(
+                user_id_user
+                    .eq(user_id)
+                    .and(lower(user_email).eq(normalize_email(verified_email))),
+            ),
+        )
+        .set(email_verified_at.eq(Some(timestamp)))
+        .execute(conn)?;
+        if verified == 0 {
+            return Err(UserError::InvalidToken);
+        }
+
+        Ok(())
+    })
+}
+
+pub fn get_user(conn: &mut PgConnection, user_id: Uuid) -> Result<RLUser, UserError> {
+    use crate::schema::rl_users::dsl::*;
+
+    rl_users
+        .filter(id_user.eq(user_id))
+        .select(RLUser::as_select())
+        .first(conn)
+        .optional()
+        .map_err(|e| anyhow!("{}", e))?
+        .ok_or(UserError::UserNotFound)
+}
+
+pub const PASSWORD_CHANGE_EVENT: &str = "PASSWORD_CHANGE";
+
+/// Stores the new hash and, when `keep_connection` is given, ends every other
+/// session of the user.
+pub fn change_password(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    password_hash: String,
+    keep_connection: Option<Uuid>,
+    client: ClientInfo,
+) -> Result<(), UserError> {
+    use crate::schema::connections::dsl::*;
+    use crate::schema::rl_users::dsl::{id_user as user_id_user, password};
+
+    conn.transaction::<_, UserError, _>(|conn| {
+        diesel::update(rl_users.filter(user_id_user.eq(user_id)))
+            .set(password.eq(password_hash))
+            .execute(conn)?;
+        if let Some(connection_id) = keep_connection {
+            diesel::update(
+                connections.filter(
+                    id_user
+                        .eq(user_id)
+                        .and(id_connection.ne(connection_id))
+                        .and(ended_at.is_null()),
+                ),
+            )
+            .set(ended_at.eq(Some(now())))
+            .execute(conn)?;
+        }
+        save_security_event(conn, user_id, PASSWORD_CHANGE_EVENT, None, client)
+    })
+}
+
+pub const PASSWORD_RESET_EVENT: &str = "PASSWORD_RESET";
+
+pub fn create_password_reset(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    reset_token_hash: String,
+) -> Result<PasswordReset, UserError> {
+    use crate::schema::password_resets::dsl::*;
+
+    let expiration_minutes: i64 = env::var("PASSWORD_RESET_EXP_MIN")
+        .expect("PASSWORD_RESET_EXP_MIN must be set.")
+        .parse()
+        .expect("PASSWORD_RESET_EXP_MIN must be a number.");
+
+    let timestamp = now();
+    let reset = PasswordReset {
+        id_password_reset: Uuid::new_v4(),
+        id_user: user_id,
+        token_hash: reset_token_hash,
+        created_at: timestamp,
+        expires_at: timestamp + chrono::Duration::minutes(expiration_minutes),
+        used_at: None,
+    };
+    diesel::insert_into(password_resets)
+        .values(&reset)
+        .execute(conn)
+        .map_err(|e| anyhow!("{}", e))?;
+
+    Ok(reset)
+}
+
+/// The user behind a reset token that is neither used nor expired.
+pub fn get_password_reset_user(
+    conn: &mut PgConnection,
+    reset_token_hash: &str,
+) -> Result<RLUser, UserError> {
+    use crate::schema::password_resets::dsl::*;
+
+    password_resets
+        .inner_join(rl_users)
+        .filter(
+            token_hash
+                .eq(reset_token_hash)
+                .and(used_at.is_null())
+                .and(expires_at.gt(now())),
+        )
+        .select(RLUser::as_select())
+        .first(conn)
+        .optional()
+        .map_err(|e| anyhow!("{}", e))?
+        .ok_or(UserError::InvalidToken)
+}
+
+/// Uses up the token together with every other pending reset of the user, stores
+/// the new hash and ends all of the user's sessions.
+pub fn reset_password(
+    conn: &mut PgConnection,
+    reset_token_hash: &str,
+    password_hash: String,
+    client: ClientInfo,
+) -> Result<(), UserError> {
+    use crate::schema::password_resets::dsl::*;
+    use crate::schema::rl_users::dsl::{id_user as user_id_user, password};
+
+    conn.transaction::<_, UserError, _>(|conn| {
+        let timestamp = now();
+        let user_id = diesel::update(
+            password_resets.filter(
+                token_hash
+                    .eq(reset_token_hash)
+                    .and(used_at.is_null())
+                    .and(expires_at.gt(timestamp)),
+            ),
+        )
+        .set(used_at.eq(Some(timestamp)))
+        .returning(id_user)
+        .get_result::<Uuid>(conn)
+        .optional()?
+        .ok_or(UserError::InvalidToken)?;
+
+        diesel::update(password_resets.filter(id_user.eq(user_id).and(used_at.is_null())))
+            .set(used_at.eq(Some(timestamp)))
+            .execute(conn)?;
+        diesel::update(rl_users.filter(user_id_user.eq(user_id)))
+            .set(password.eq(password_hash))
+            .execute(conn)?;
+        end_all_connections(conn, user_id)?;
+        save_security_event(conn, user_id, PASSWORD_RESET_EVENT, None, client)
+    })
+}
+
+pub const EMAIL_CHANGE_EVENT: &str = "EMAIL_CHANGE";
+pub const EMAIL_CHANGE_CANCEL_EVENT: &str = "EMAIL_CHANGE_CANCEL";
+
+/// Supersedes any change the user still has pending.
+pub fn create_email_change(
+    conn: &mut PgConnection,
+    user: &RLUser,
+    new_address: &str,
+    confirm_token_hash: String,
+    cancel_hash: String,
+) -> Result<EmailChange, UserError> {
+    use crate::schema::email_changes::dsl::*;
+
+    let expiration_hours: i64 = env::var("EMAIL_CHANGE_EXP_HOUR")
+        .expect("EMAIL_CHANGE_EXP_HOUR must be set.")
+        .parse()
+        .expect("EMAIL_CHANGE_EXP_HOUR must be a number.");
+
+    if get_user_by_email(conn, new_address)?.is_some() {
+        return Err(UserError::EmailNotAvailable);
+    }
+
+    let timestamp = now();
+    let change = EmailChange {
+        id_email_change: Uuid::new_v4(),
+        id_user: user.id_user,
+        old_email: user.email.clone(),
+        new_email: normalize_email(new_address),
+        token_hash: confirm_token_hash,
+        cancel_token_hash: cancel_hash,
+        created_at: timestamp,
+        expires_at: timestamp + chrono::Duration::hours(expiration_hours),
+        confirmed_at: None,
+        cancelled_at: None,
+    };
+    conn.transaction::<_, UserError, _>(|conn| {
+        diesel::update(
+            email_changes.filter(
+                id_user
+                    .eq(user.id_user)
+                    .and(confirmed_at.is_null())
+                    .and(cancelled_at.is_null()),
+            ),
+        )
+        .set(cancelled_at.eq(Some(timestamp)))
+        .execute(conn)?;
+        diesel::insert_into(email_changes)
+            .values(&change)
+            .execute(conn)?;
+        Ok(())
+    })?;
+
+    Ok(change)
+}
+
+/// Swaps the new address in, provided the change is still pending and the user
+/// still has the address it was requested from. Following the link proves the
+/// new address, so it counts as verified.
+pub fn confirm_email_change(
+    conn: &mut PgConnection,
+    confirm_token_hash: &str,
+    client: ClientInfo,
+) -> Result<(), UserError> {
+    use crate::schema::email_changes::dsl::*;
+    use crate::schema::rl_users::dsl::{email, email_verified_at, id_user as user_id_user};
+
+    conn.transaction::<_, UserError, _>(|conn| {
+        let timestamp = now();
+        let change = email_changes
+            .filter(
+                token_hash
+                    .eq(confirm_token_hash)
+                    .and(confirmed_at.is_null())
+                    .and(cancelled_at.is_null())
+                    .and(expires_at.gt(timestamp)),
+            )
+            .select(EmailChange::as_select())
+            .for_update()
+            .first(conn)
+            .optional()?
+            .ok_or(UserError::InvalidToken)?;
+
+        let updated = diesel::update(
+            rl_users.filter(
+                user_id_user
+                    .eq(change.id_user)
+                    .and(email.eq(&change.old_email)),
+            ),
+        )
+        .set((
+            email.eq(&change.new_email),
+            email_verified_at.eq(Some(timestamp)),
+        ))
+        .execute(conn)
+        .map_err(email_taken)?;
+        if updated == 0 {
+            return Err(UserError::InvalidToken);
+        }
+
+        diesel::update(email_changes.filter(id_email_change.eq(change.id_email_change)))
+            .set(confirmed_at.eq(Some(timestamp)))
+            .execute(conn)?;
+        save_security_event(conn, change.id_user, EMAIL_CHANGE_EVENT, None, client)
+    })
+}
+
+/// Backs a change out, even after it was confirmed: then the old address may be
+/// reclaiming a hijacked account, so every session is ended as well.
+pub fn cancel_email_change(
+    conn: &mut PgConnection,
+    cancel_hash: &str,
+    client: ClientInfo,
+) -> Result<(), UserError> {
+    use crate::schema::email_changes::dsl::*;
+    use crate::schema::rl_users::dsl::{email, email_verified_at, id_user as user_id_user};
+
+    conn.transaction::<_, UserError, _>(|conn| {
+        let timestamp = now();
+        let change = email_changes
+            .filter(
+                cancel_token_hash
+                    .eq(cancel_hash)
+                    .and(cancelled_at.is_null())
+                    .and(expires_at.gt(timestamp)),
+            )
+            .select(EmailChange::as_select())
+            .for_update()
+            .first(conn)
+            .optional()?
+            .ok_or(UserError::InvalidToken)?;
+
+        if change.confirmed_at.is_some() {
+            diesel::update(
+                rl_users.filter(
+                    user_id_user
+                        .eq(change.id_user)
+                        .and(email.eq(&change.new_email)),
+                ),
+            )
+            .set((
+                email.eq(&change.old_email),
+                email_verified_at.eq(Some(timestamp)),
+            ))
+            .execute(conn)
+            .map_err(email_taken)?;
+            end_all_connections(conn, change.id_user)?;
+        }
+
+        diesel::update(email_changes.filter(id_email_change.eq(change.id_email_change)))
+            .set(cancelled_at.eq(Some(timestamp)))
+            .execute(conn)?;
+        save_security_event(
+            conn,
+            change.id_user,
+            EMAIL_CHANGE_CANCEL_EVENT,
+            None,
+            client,
+        )
+    })
+}
+
+pub const MFA_ENABLED_EVENT: &str = "MFA_ENABLED";
+pub const MFA_DISABLED_EVENT: &str = "MFA_DISABLED";
+
+/// Only a confirmed secret protects logins.
+pub fn get_confirmed_totp(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<Option<UserTotp>, UserError> {
+    use crate::schema::user_totp::dsl::*;
+
+    let totp = user_totp
+        .filter(id_user.eq(user_id).and(confirmed_at.is_not_null()))
+        .select(UserTotp::as_select())
+        .first(conn)
+        .optional()
+        .map_err(|e| anyhow!("{}", e))?;
+
+    Ok(totp)
+}
+
+pub fn get_pending_totp(conn: &mut PgConnection, user_id: Uuid) -> Result<UserTotp, UserError> {
+    use crate::schema::user_totp::dsl::*;
+
+    user_totp
+        .filter(id_user.eq(user_id).and(confirmed_at.is_null()))
+        .select(UserTotp::as_select())
+        .first(conn)
+        .optional()
+        .map_err(|e| anyhow!("{}", e))?
+        .ok_or(UserError::MfaNotEnrolled)
+}
+
+/// Replaces any enrollment the user started but never confirmed.
+pub fn start_totp_enrollment(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    totp_secret: String,
+) -> Result<(), UserError> {
+    use crate::schema::user_totp::dsl::*;
+
+    if get_confirmed_totp(conn, user_id)?.is_some() {
+        return Err(UserError::MfaAlreadyEnabled);
+    }
+
+    let totp = UserTotp {
+        id_user: user_id,
+        secret: totp_secret,
+        created_at: now(),
+        confirmed_at: None,
+        last_used_step: None,
+    };
+    diesel::insert_into(user_totp)
+        .values(&totp)
+        .on_conflict(id_user)
+        .do_update()
+        .set((
+            secret.eq(&totp.secret),
+            created_at.eq(totp.created_at),
+            last_used_step.eq(None::<i64>),
+        ))
+        .execute(conn)
+        .map_err(|e| anyhow!("{}", e))?;
+
+    Ok(())
+}
+
+/// Turns the pending secret on and replaces the user's recovery codes.
+pub fn confirm_totp(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    step: i64,
+    code_hashes: Vec<String>,
+    client: ClientInfo,
+) -> Result<(), UserError> {
+    use crate::schema::recovery_codes::dsl::{id_user as code_id_user, recovery_codes};
+    use crate::schema::user_totp::dsl::*;
+
+    conn.transaction::<_, UserError, _>(|conn| {
+        let confirmed =
+            diesel::update(user_totp.filter(id_user.eq(user_id).and(confirmed_at.is_null())))
+                .set((confirmed_at.eq(Some(now())), last_used_step.eq(Some(step))))
+                .execute(conn)?;
+        if confirmed == 0 {
+            return Err(UserError::MfaNotEnrolled);
+        }
+
+        diesel::delete(recovery_codes.filter(code_id_user.eq(user_id))).execute(conn)?;
+        diesel::insert_into(recovery_codes)
+            .values(
+                code_hashes
+                    .into_iter()
+                    .map(|code_hash| RecoveryCode {
+                        id_recovery_code: Uuid::new_v4(),
+                        id_user: user_id,
+                        code_hash,
+                        used_at: None,
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .execute(conn)?;
+        save_security_event(conn, user_id, MFA_ENABLED_EVENT, None, client)
+    })
+}
+
+pub fn disable_totp(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    client: ClientInfo,
+) -> Result<(), UserError> {
+    use crate::schema::recovery_codes::dsl::{id_user as code_id_user, recovery_codes};
+    use crate::schema::user_totp::dsl::*;
+
+    conn.transaction::<_, UserError, _>(|conn| {
+        let deleted = diesel::delete(user_totp.filter(id_user.eq(user_id))).execute(conn)?;
+        if deleted == 0 {
+            return Err(UserError::MfaNotEnrolled);
+        }
+        diesel::delete(recovery_codes.filter(code_id_user.eq(user_id))).execute(conn)?;
+        save_security_event(conn, user_id, MFA_DISABLED_EVENT, None, client)
+    })
+}
+
+/// Records `step` as used unless it, or a later one, already was. Concurrent logins
+/// with the same code race on this update and only one of them wins.
+pub fn use_totp_step(conn: &mut PgConnection, user_id: Uuid, step: i64) -> Result<bool, UserError> {
+    use crate::schema::user_totp::dsl::*;
+
+    let updated = diesel::update(
+        user_totp.filter(
+            id_user
+                .eq(user_id)
+                .and(confirmed_at.is_not_null())
+                .and(last_used_step.is_null().or(last_used_step.lt(step))),
+        ),
+    )
+    .set(last_used_step.eq(Some(step)))
+    .execute(conn)
+    .map_err(|e| anyhow!("{}", e))?;
+
+    Ok(updated == 1)
+}
+
+pub fn use_recovery_code(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    recovery_code_hash: &str,
+) -> Result<bool, UserError> {
+    use crate::schema::recovery_codes::dsl::*;
+
+    let updated = diesel::update(
+        recovery_codes.filter(
+            id_user
+                .eq(user_id)
+                .and(code_hash.eq(recovery_code_hash))
+                .and(used_at.is_null()),
+        ),
+    )
+    .set(used_at.eq(Some(now())))
+    .execute(conn)
+    .map_err(|e| anyhow!("{}", e))?;
+
+    Ok(updated == 1)
+}
+
+/// Stores a sign-in code for the user, replacing any code sent before.
+pub fn create_login_code(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    code: &str,
+) -> Result<LoginCode, UserError> {
+    use crate::schema::login_codes::dsl::*;
+
+    let expiration_minutes: i64 = env::var("LOGIN_CODE_EXP_MIN")
+        .expect("LOGIN_CODE_EXP_MIN must be set.")
+        .parse()
+        .expect("LOGIN_CODE_EXP_MIN must be a number.");
+
+    let timestamp = now();
+    let login_code_id = Uuid::new_v4();
+    let login_code = LoginCode {
+        id_login_code: login_code_id,
+        id_user: user_id,
+        code_hash: hash_code(login_code_id, code),
+        created_at: timestamp,
+        expires_at: timestamp + chrono::Duration::minutes(expiration_minutes),
+        used_at: None,
+        failed_attempts: 0,
+    };
+    conn.transaction::<_, UserError, _>(|conn| {
+        diesel::update(login_codes.filter(id_user.eq(user_id).and(used_at.is_null())))
+            .set(used_at.eq(Some(timestamp)))
+            .execute(conn)?;
+        diesel::insert_into(login_codes)
+            .values(&login_code)
+            .execute(conn)?;
+        Ok(())
+    })?;
+
+    Ok(login_code)
+}
+
+/// Checks `code` against the user's pending sign-in code and uses it up on a
+/// match. After LOGIN_CODE_MAX_ATTEMPTS wrong guesses the code is burnt, so a six
+/// digit code can't be brute forced within its lifetime.
+pub fn use_login_code(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    code: &str,
+) -> Result<bool, UserError> {
+    use crate::schema::login_codes::dsl::*;
+
+    let max_attempts: i32 = env::var("LOGIN_CODE_MAX_ATTEMPTS")
+        .expect("LOGIN_CODE_MAX_ATTEMPTS must be set.")
+        .parse()
+        .expect("LOGIN_CODE_MAX_ATTEMPTS must be a number.");
+
+    conn.transaction::<_, UserError, _>(|conn| {
+        let timestamp = now();
+        let Some(login_code) = login_codes
+            .filter(
+                id_user
+                    .eq(user_id)
+                    .and(used_at.is_null())
+                    .and(expires_at.gt(timestamp)),
+            )
+            .order(created_at.desc())
+            .select(LoginCode::as_select())
+            .for_update()
+            .first(conn)
+            .optional()?
+        else {
+            return Ok(false);
+        };
+
+        let target = login_codes.filter(id_login_code.eq(login_code.id_login_code));
+        if login_code.code_hash == hash_code(login_code.id_login_code, code) {
+            diesel::update(target)
+                .set(used_at.eq(Some(timestamp)))
+                .execute(conn)?;
+            return Ok(true);
+        }
+
+        let attempts = login_code.failed_attempts + 1;
+        diesel::update(target)
+            .set((
+                failed_attempts.eq(attempts),
+                used_at.eq((attempts >= max_attempts).then_some(timestamp)),
+            ))
+            .execute(conn)?;
+        Ok(false)
+    })
+}
+
+pub const PASSKEY_ADDED_EVENT: &str = "PASSKEY_ADDED";
+pub const PASSKEY_REMOVED_EVENT: &str = "PASSKEY_REMOVED";
+
+/// Stores a challenge for one registration or login ceremony. Expired challenges
+/// nobody came back for are cleaned up on the way.
+pub fn create_webauthn_challenge(
+    conn: &mut PgConnection,
+    user_id: Option<Uuid>,
+    challenge_ceremony: &str,
+) -> Result<WebauthnChallenge, UserError> {
+    use crate::schema::webauthn_challenges::dsl::*;
+
+    let expiration_seconds: i64 = env::var("WEBAUTHN_CHALLENGE_EXP_SEC")
+        .expect("WEBAUTHN_CHALLENGE_EXP_SEC must be set.")
+        .parse()
+        .expect("WEBAUTHN_CHALLENGE_EXP_SEC must be a number.");
+
+    let timestamp = now();
+    let webauthn_challenge = WebauthnChallenge {
+        id_webauthn_challenge: Uuid::new_v4(),
+        id_user: user_id,
+        challenge: generate_token(),
+        ceremony: challenge_ceremony.to_string(),
+        created_at: timestamp,
+        expires_at: timestamp + chrono::Duration::seconds(expiration_seconds),
+    };
+    conn.transaction::<_, UserError, _>(|conn| {
+        diesel::delete(webauthn_challenges.filter(expires_at.le(timestamp))).execute(conn)?;
+        diesel::insert_into(webauthn_challenges)
+            .values(&webauthn_challenge)
+            .execute(conn)?;
+        Ok(())
+    })?;
+
+    Ok(webauthn_challenge)
+}
+
+/// Deletes the challenge as it is read, so each one can only be answered once.
+pub fn take_webauthn_challenge(
+    conn: &mut PgConnection,
+    challenge_id: Uuid,
+    user_id: Option<Uuid>,
+    challenge_ceremony: &str,
+) -> Result<WebauthnChallenge, UserError> {
+    use crate::schema::webauthn_challenges::dsl::*;
+
+    let webauthn_challenge = diesel::delete(
+        webauthn_challenges.filter(
+            id_webauthn_challenge
+                .eq(challenge_id)
+                .and(ceremony.eq(challenge_ceremony))
+                .and(expires_at.gt(now())),
+        ),
+    )
+    .returning(WebauthnChallenge::as_returning())
+    .get_result(conn)
+    .optional()
+    .map_err(|e| anyhow!("{}", e))?
+    .filter(|webauthn_challenge| webauthn_challenge.id_user == user_id)
+    .ok_or(UserError::InvalidPasskey)?;
+
+    Ok(webauthn_challenge)
+}
+
+pub fn save_webauthn_credential(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    registered: RegisteredCredential,
+    credential_label: Option<String>,
+    client: ClientInfo,
+) -> Result<WebauthnCredential, UserError> {
+    use crate::schema::webauthn_credentials::dsl::*;
+
+    let credential = WebauthnCredential {
+        id_webauthn_credential: Uuid::new_v4(),
+        id_user: user_id,
+        credential_id: registered.credential_id,
+        public_key: registered.public_key,
+        sign_count: registered.sign_count,
+        label: credential_label,
+        created_at: now(),
+        last_used_at: None,
+    };
+    conn.transaction::<_, UserError, _>(|conn| {
+        diesel::insert_into(webauthn_credentials)
+            .values(&credential)
+            .execute(conn)
+            .map_err(|e| match e {
+                diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                    UserError::InvalidPasskey
+                }
+                e => UserError::from(e),
+            })?;
+        save_security_event(conn, user_id, PASSKEY_ADDED_EVENT, None, client)
+    })?;
+
+    Ok(credential)
+}
+
+pub fn get_webauthn_credentials(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<Vec<WebauthnCredential>, UserError> {
+    use crate::schema::webauthn_credentials::dsl::*;
+
+    let credentials = webauthn_credentials
+        .filter(id_user.eq(user_id))
+        .order(created_at.asc())
+        .select(WebauthnCredential::as_select())
+        .load(conn)
+        .map_err(|e| anyhow!("{}", e))?;
+
+    Ok(credentials)
+}
+
+pub fn get_webauthn_credential(
+    conn: &mut PgConnection,
+    webauthn_credential_id: &str,
+) -> Result<WebauthnCredential, UserError> {
+    use crate::schema::webauthn_credentials::dsl::*;
+
+    webauthn_credentials
+        .filter(credential_id.eq(webauthn_credential_id))
+        .select(WebauthnCredential::as_select())
+        .first(conn)
+        .optional()
+        .map_err(|e| anyhow!("{}", e))?
+        .ok_or(UserError::InvalidPasskey)
+}
+
+/// Moves the signature counter forward from the value the assertion was checked
+/// against. Two logins racing with the same assertion can't both win.
+pub fn use_webauthn_credential(
+    conn: &mut PgConnection,
+    credential: &WebauthnCredential,
+    new_sign_count: i64,
+) -> Result<bool, UserError> {
+    use crate::schema::webauthn_credentials::dsl::*;
+
+    let updated = diesel::update(
+        webauthn_credentials.filter(
+            id_webauthn_credential
+                .eq(credential.id_webauthn_credential)
+                .and(sign_count.eq(credential.sign_count)),
+        ),
+    )
+    .set((sign_count.eq(new_sign_count), last_used_at.eq(Some(now()))))
+    .execute(conn)
+    .map_err(|e| anyhow!("{}", e))?;
+
+    Ok(updated == 1)
+}
+
+pub fn delete_webauthn_credential(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    credential_id_to_delete: Uuid,
+    client: ClientInfo,
+) -> Result<(), UserError> {
+    use crate::schema::webauthn_credentials::dsl::*;
+
+    conn.transaction::<_, UserError, _>(|conn| {
+        let deleted = diesel::delete(
+            webauthn_credentials.filter(
+                id_user
+                    .eq(user_id)
+                    .and(id_webauthn_credential.eq(credential_id_to_delete)),
+            ),
+        )
+        .execute(conn)?;
+        if deleted == 0 {
+            return Err(UserError::PasskeyNotFound);
+        }
+        save_security_event(conn, user_id, PASSKEY_REMOVED_EVENT, None, client)
+    })
+}
+
+pub fn get_oauth_client(
+    conn: &mut PgConnection,
+    oauth_client_id: &str,
+) -> Result<Option<OAuthClient>, UserError> {
+    use crate::schema::oauth_clients::dsl::*;
+
+    let client = oauth_clients
+        .filter(client_id.eq(oauth_client_id))
+        .select(OAuthClient::as_select())
+        .first(conn)
+        .optional()
+        .map_err(|e| anyhow!("{}", e))?;
+
+    Ok(client)
+}
+
+pub fn create_authorization_code(
+    conn: &mut PgConnection,
+    client: &OAuthClient,
+    user_id: Uuid,
+    authorization_code_hash: String,
+    request: &AuthorizationRequest,
+    granted_scope: &str,
+) -> Result<OAuthAuthorizationCode, UserError> {
+    use crate::schema::oauth_authorization_codes::dsl::*;
+
+    let expiration_seconds: i64 = env::var("OAUTH_CODE_EXP_SEC")
+        .expect("OAUTH_CODE_EXP_SEC must be set.")
+        .parse()
+        .expect("OAUTH_CODE_EXP_SEC must be a number.");
+
+    let timestamp = now();
+    let authorization_code = OAuthAuthorizationCode {
+        id_oauth_authorization_code: Uuid::new_v4(),
+        code_hash: authorization_code_hash,
+        id_oauth_client: client.id_oauth_client,
+        id_user: user_id,
+        redirect_uri: request.redirect_uri.clone(),
+        scope: granted_scope.to_string(),
+        code_challenge: request.code_challenge.clone().unwrap_or_default(),
+        created_at: timestamp,
+        expires_at: timestamp + chrono::Duration::seconds(expiration_seconds),
+        used_at: None,
+        nonce: request.nonce.clone(),
+    };
+    diesel::insert_into(oauth_authorization_codes)
+        .values(&authorization_code)
+        .execute(conn)
+        .map_err(|e| anyhow!("{}", e))?;
+
+    Ok(authorization_code)
+}
+
+/// Uses up the code whether or not the rest of the token request turns out valid.
+pub fn use_authorization_code(
+    conn: &mut PgConnection,
+    authorization_code_hash: &str,
+) -> Result<OAuthAuthorizationCode, UserError> {
+    use crate::schema::oauth_authorization_codes::dsl::*;
+
+    let timestamp = now();
+    diesel::update(
+        oauth_authorization_codes.filter(
+            code_hash
+                .eq(authorization_code_hash)
+                .and(used_at.is_null())
+                .and(expires_at.gt(timestamp)),
+        ),
+    )
+    .set(used_at.eq(Some(timestamp)))
+    .returning(OAuthAuthorizationCode::as_returning())
+    .get_result(conn)
+    .optional()
+    .map_err(|e| anyhow!("{}", e))?
+    .ok_or(UserError::InvalidToken)
+}
+
+fn now() -> NaiveDateTime {
+    DateTime::from_timestamp(Local::now().timestamp(), 0)
+        .unwrap()
+        .naive_utc()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection(minutes_ago: i64) -> ConnectionModel {
+        let id = Uuid::new_v4();
+        ConnectionModel {
+            id_connection: id,
+            id_user: Uuid::nil(),
+            connect_at: Some(now() - chrono::Duration::minutes(minutes_ago)),
+            ended_at: None,
+            id_session: id,
+            user_agent: None,
+            ip_address: None,
+            device_label: None,
+            refreshed_at: None,
+            rotated_at: None,
+        }
+    }
+
+    #[test]
+    fn evicts_the_oldest_sessions_to_make_room_for_a_new_one() {
+        let active = vec![connection(30), connection(20), connection(10)];
+
+        assert!(connections_to_evict(&active, 5).is_empty());
+        assert!(connections_to_evict(&active, 4).is_empty());
+        assert_eq!(connections_to_evict(&active, 3), &active[..1]);
+        assert_eq!(connections_to_evict(&active, 1), &active[..]);
+        assert_eq!(connections_to_evict(&active, 0), &active[..]);
+    }
+
+    /// The connections table and security events, for `rotate_session`.
+    #[derive(Default)]
+    struct MemorySessions {
+        connections: Vec<ConnectionModel>,
+        events: Vec<(String, Option<Uuid>)>,
+    }
+
+    impl SessionStore for MemorySessions {
+        fn in_transaction<T>(
+            &mut self,
+            f: impl FnOnce(&mut Self) -> Result<T, UserError>,
+        ) -> Result<T, UserError> {
+            f(self)
+        }
+
+        fn lock_connection(
+            &mut self,
+            user_id: Uuid,
+            connection_id: Uuid,
+        ) -> Result<Option<ConnectionModel>, UserError> {
+            Ok(self
+                .connections
+                .iter()
+                .find(|con| con.id_user == user_id && con.id_connection == connection_id)
+                .cloned())
+        }
+
+        fn end_session(
+            &mut self,
+            session_id: Uuid,
+            timestamp: NaiveDateTime,
+        ) -> Result<(), UserError> {
+            self.connections
+                .iter_mut()
+                .filter(|con| con.id_session == session_id && con.ended_at.is_none())
+                .for_each(|con| con.ended_at = Some(timestamp));
+            Ok(())
+        }
+
+        fn end_rotated_connection(
+            &mut self,
+            connection_id: Uuid,
+            timestamp: NaiveDateTime,
+        ) -> Result<bool, UserError> {
+            let Some(con) = self
+                .connections
+                .iter_mut()
+                .find(|con| con.id_connection == connection_id && con.ended_at.is_none())
+            else {
+                return Ok(false);
+            };
+            con.ended_at = Some(timestamp);
+            con.rotated_at = Some(timestamp);
+            Ok(true)
+        }
+
+        fn insert_connection(&mut self, connection: ConnectionModel) -> Result<(), UserError> {
+            self.connections.push(connection);
+            Ok(())
+        }
+
+        fn save_security_event(
+            &mut self,
+            _: Uuid,
+            event: &str,
+            session_id: Option<Uuid>,
+            _: ClientInfo,
+        ) -> Result<(), UserError> {
+            self.events.push((event.to_string(), session_id));
+            Ok(())
+        }
+    }
+
+    fn active(store: &MemorySessions, connection_id: Uuid) -> bool {
+        store
+            .connections
+            .iter()
+            .any(|con| con.id_connection == connection_id && con.ended_at.is_none())
+    }
+
+    #[test]
+    fn reusing_a_rotated_refresh_token_ends_the_whole_session() {
+        let login = connection(5);
+        let (user_id, session_id) = (login.id_user, login.id_session);
+        let other_device = connection(1);
+        let mut store = MemorySessions {
+            connections: vec![login.clone(), other_device.clone()],
+            ..Default::default()
+        };
+
+        let refreshed = rotate_session(
+            &mut store,
+            user_id,
+            login.id_connection,
+            ClientInfo::default(),
+        )
+        .unwrap();
+        assert!(!active(&store, login.id_connection));
+        assert!(active(&store, refreshed));
+
+        let reused = rotate_session(
+            &mut store,
+            user_id,
+            login.id_connection,
+            ClientInfo::default(),
+        );
+
+        assert!(matches!(reused, Err(UserError::RefreshTokenReused)));
+        assert!(!active(&store, refreshed));
+        assert!(active(&store, other_device.id_connection));
+        assert_eq!(
+            store.events,
+            vec![(REFRESH_TOKEN_REUSE_EVENT.to_string(), Some(session_id))]
+        );
+        assert!(matches!(
+            rotate_session(&mut store, user_id, refreshed, ClientInfo::default()),
+            Err(UserError::ExpiredToken)
+        ));
+    }
+}
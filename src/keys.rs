@@ -0,0 +1,633 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock, RwLock};
+
+use anyhow::{anyhow, bail, Context};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ed25519_dalek::pkcs8::DecodePublicKey;
+use ed25519_dalek::VerifyingKey;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+    OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rsa::pkcs1::DecodeRsaPublicKey;
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPublicKey;
+use serde::{Deserialize, Serialize};
+
+static KEYRING: OnceLock<RwLock<Arc<Keyring>>> = OnceLock::new();
+
+pub struct JwtKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    pub encoding_key: Option<EncodingKey>,
+    pub decoding_key: DecodingKey,
+    pub jwk: Option<Jwk>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Probe {
+    exp: i64,
+}
+
+enum KeyMaterial {
+    Secret(Vec<u8>),
+    Pem {
+        private_key: Option<Vec<u8>>,
+        public_key: Vec<u8>,
+    },
+}
+
+impl JwtKey {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let algorithm = env::var("JWT_ALGORITHM").unwrap_or_else(|_| "HS256".to_string());
+        let kid = env::var("JWT_KEY_ID").unwrap_or_else(|_| "default".to_string());
+
+        let material = if algorithm == "HS256" {
+            KeyMaterial::Secret(
+                env::var("SECRET")
+                    .context("SECRET must be set")?
+                    .into_bytes(),
+            )
+        } else {
+            let private_key_path =
+                env::var("JWT_PRIVATE_KEY_PATH").context("JWT_PRIVATE_KEY_PATH must be set")?;
+            let public_key_path =
+                env::var("JWT_PUBLIC_KEY_PATH").context("JWT_PUBLIC_KEY_PATH must be set")?;
+            KeyMaterial::Pem {
+                private_key: Some(read_file(&private_key_path)?),
+                public_key: read_file(&public_key_path)?,
+            }
+        };
+
+        Self::new(kid, &algorithm, material)
+    }
+
+    fn from_config(config: &KeyConfig) -> anyhow::Result<Self> {
+        let material = match (&config.secret_path, &config.public_key_path) {
+            (Some(secret_path), _) => {
+                KeyMaterial::Secret(read_file(secret_path)?.trim_ascii().to_vec())
+            }
+            (None, Some(public_key_path)) => KeyMaterial::Pem {
+                private_key: config
+                    .private_key_path
+                    .as_ref()
+                    .map(|path| read_file(path))
+                    .transpose()?,
+                public_key: read_file(public_key_path)?,
+            },
+            (None, None) => bail!(
+                "Key {} needs either a secretPath or a publicKeyPath",
+                config.kid
+            ),
+        };
+
+        Self::new(config.kid.clone(), &config.algorithm, material)
+    }
+
+    fn new(kid: String, algorithm: &str, material: KeyMaterial) -> anyhow::Result<Self> {
+        let algorithm = Algorithm::from_str(algorithm)
+            .map_err(|_| anyhow!("Unknown JWT algorithm {}", algorithm))?;
+
+        let (encoding_key, decoding_key, jwk) = match (algorithm, material) {
+            (Algorithm::HS256, KeyMaterial::Secret(secret)) => (
+                Some(EncodingKey::from_secret(&secret)),
+                DecodingKey::from_secret(&secret),
+                None,
+            ),
+            (
+                Algorithm::RS256,
+                KeyMaterial::Pem {
+                    private_key,
+                    public_key,
+                },
+            ) => (
+                private_key
+                    .map(|key| EncodingKey::from_rsa_pem(&key))
+                    .transpose()?,
+                DecodingKey::from_rsa_pem(&public_key)?,
+                Some(rsa_jwk(&kid, &public_key)?),
+            ),
+            (
+                Algorithm::EdDSA,
+                KeyMaterial::Pem {
+                    private_key,
+                    public_key,
+                },
+            ) => (
+                private_key
+                    .map(|key| EncodingKey::from_ed_pem(&key))
+                    .transpose()?,
+                DecodingKey::from_ed_pem(&public_key)?,
+                Some(ed_jwk(&kid, &public_key)?),
+            ),
+            (algorithm, _) => bail!("Unsupported key {} for {:?}", kid, algorithm),
+        };
+
+        let key = Self {
+            kid,
+            algorithm,
+            encoding_key,
+            decoding_key,
+            jwk,
+        };
+        key.check_pair()?;
+        Ok(key)
+    }
+
+    /// A private key from one pair and a public key from another would sign tokens
+    /// nobody can verify, so a probe token has to survive a round trip first.
+    fn check_pair(&self) -> anyhow::Result<()> {
+        let Some(encoding_key) = &self.encoding_key else {
+            return Ok(());
+        };
+        let probe = Probe {
+            exp: chrono::Utc::now().timestamp() + 60,
+        };
+        let token = jsonwebtoken::encode(&Header::new(self.algorithm), &probe, encoding_key)?;
+        jsonwebtoken::decode::<Probe>(&token, &self.decoding_key, &Validation::new(self.algorithm))
+            .with_context(|| {
+                format!(
+                    "The private and public key of {} don't belong together",
+                    self.kid
+                )
+            })?;
+        Ok(())
+    }
+
+    pub fn encoding_key(&self) -> anyhow::Result<&EncodingKey> {
+        self.encoding_key
+            .as_ref()
+            .ok_or_else(|| anyhow!("Key {} can only verify tokens", self.kid))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KeyringConfig {
+    active_kid: String,
+    keys: Vec<KeyConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KeyConfig {
+    kid: String,
+    algorithm: String,
+    private_key_path: Option<String>,
+    public_key_path: Option<String>,
+    secret_path: Option<String>,
+}
+
+pub struct Keyring {
+    active_kid: String,
+    keys: HashMap<String, JwtKey>,
+}
+
+impl Keyring {
+    pub fn load() -> anyhow::Result<Self> {
+        let Ok(path) = env::var("JWT_KEYRING_PATH") else {
+            let key = JwtKey::from_env()?;
+            return Ok(Self {
+                active_kid: key.kid.clone(),
+                keys: HashMap::from([(key.kid.clone(), key)]),
+            });
+        };
+
+        let config: KeyringConfig = serde_json::from_slice(&read_file(&path)?)
+            .with_context(|| format!("Invalid keyring {}", path))?;
+        Self::from_config(config)
+    }
+
+    /// Retired keys stay in the keyring without a private key, so tokens they
+    /// signed keep verifying until they expire.
+    fn from_config(config: KeyringConfig) -> anyhow::Result<Self> {
+        let keys = config
+            .keys
+            .iter()
+            .map(|key| JwtKey::from_config(key).map(|jwt_key| (key.kid.clone(), jwt_key)))
+            .collect::<anyhow::Result<HashMap<_, _>>>()?;
+
+        keys.get(&config.active_kid)
+            .ok_or_else(|| anyhow!("Active key {} is not in the keyring", config.active_kid))?
+            .encoding_key()?;
+
+        Ok(Self {
+            active_kid: config.active_kid,
+            keys,
+        })
+    }
+
+    pub fn signing_key(&self) -> &JwtKey {
+        &self.keys[&self.active_kid]
+    }
+
+    /// Tokens other parties verify, like ID tokens, need a key they can fetch from
+    /// the JWK set. Handing out a shared secret would let them forge access tokens.
+    pub fn public_signing_key(&self) -> Option<&JwtKey> {
+        Some(self.signing_key()).filter(|key| key.jwk.is_some())
+    }
+
+    /// Tokens issued before key ids were emitted carry no `kid` and are checked
+    /// against the active key.
+    pub fn verification_key(&self, kid: Option<&str>) -> Option<&JwtKey> {
+        self.keys.get(kid.unwrap_or(&self.active_kid))
+    }
+
+    pub fn jwk_set(&self) -> JwkSet {
+        JwkSet {
+            keys: self
+                .keys
+                .values()
+                .filter_map(|key| key.jwk.clone())
+                .collect(),
+        }
+    }
+}
+
+pub fn keyring() -> Arc<Keyring> {
+    keyring_lock()
+        .read()
+        .expect("Keyring lock poisoned.")
+        .clone()
+}
+
+pub fn reload_keyring() -> anyhow::Result<()> {
+    let keyring = Keyring::load()?;
+    *keyring_lock()
+        .write()
+        .map_err(|_| anyhow!("Keyring lock poisoned."))? = Arc::new(keyring);
+    Ok(())
+}
+
+#[cfg(unix)]
+pub async fn reload_on_hangup() {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            log::error!("Couldn't listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        match reload_keyring() {
+            Ok(_) => log::info!("Signing keyring reloaded."),
+            Err(e) => log::error!("Couldn't reload signing keyring: {}", e),
+        }
+    }
+}
+
+fn keyring_lock() -> &'static RwLock<Arc<Keyring>> {
+    KEYRING.get_or_init(|| {
+        RwLock::new(Arc::new(
+            Keyring::load().expect("JWT signing keys must be configured."),
+        ))
+    })
+}
+
+fn rsa_jwk(kid: &str, public_key: &[u8]) -> anyhow::Result<Jwk> {
+    let pem = std::str::from_utf8(public_key)?;
+    let public_key = RsaPublicKey::from_public_key_pem(pem)
+        .or_else(|_| RsaPublicKey::from_pkcs1_pem(pem))
+        .map_err(|e| anyhow!("Invalid RSA public key: {}", e))?;
+
+    Ok(Jwk {
+        common: common_parameters(kid, KeyAlgorithm::RS256),
+        algorithm: AlgorithmParameters::RSA(RSAKeyParameters {
+            key_type: RSAKeyType::RSA,
+            n: URL_SAFE_NO_PAD.encode(public_key.n().to_bytes_be()),
+            e: URL_SAFE_NO_PAD.encode(public_key.e().to_bytes_be()),
+        }),
+    })
+}
+
+fn ed_jwk(kid: &str, public_key: &[u8]) -> anyhow::Result<Jwk> {
+    let pem = std::str::from_utf8(public_key)?;
+    let public_key = VerifyingKey::from_public_key_pem(pem)
+        .map_err(|e| anyhow!("Invalid Ed25519 public key: {}", e))?;
+
+    Ok(Jwk {
+        common: common_parameters(kid, KeyAlgorithm::EdDSA),
+        algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+            key_type: OctetKeyPairType::OctetKeyPair,
+            curve: EllipticCurve::Ed25519,
+            x: URL_SAFE_NO_PAD.encode(public_key.as_bytes()),
+        }),
+    })
+}
+
+fn common_parameters(kid: &str, algorithm: KeyAlgorithm) -> CommonParameters {
+    CommonParameters {
+        public_key_use: Some(PublicKeyUse::Signature),
+        key_algorithm: Some(algorithm),
+        key_id: Some(kid.to_string()),
+        ..Default::default()
+    }
+}
+
+fn read_file(path: &str) -> anyhow::Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("Couldn't read {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{decode, encode};
+
+    use super::*;
+
+    const RSA_PRIVATE_KEY: &[u8] = include_bytes!("../tests/fixtures/rs256.pem");
+    const RSA_PUBLIC_KEY: &[u8] = include_bytes!("../tests/fixtures/rs256.pub.pem");
+    const OTHER_RSA_PUBLIC_KEY: &[u8] = include_bytes!("../tests/fixtures/rs256-other.pub.pem");
+    const ED_PRIVATE_KEY: &[u8] = include_bytes!("../tests/fixtures/eddsa.pem");
+    const ED_PUBLIC_KEY: &[u8] = include_bytes!("../tests/fixtures/eddsa.pub.pem");
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Claims {
+        sub: String,
+        exp: i64,
+    }
+
+    fn pem(private_key: Option<&[u8]>, public_key: &[u8]) -> KeyMaterial {
+        KeyMaterial::Pem {
+            private_key: private_key.map(|key| key.to_vec()),
+            public_key: public_key.to_vec(),
+        }
+    }
+
+    fn round_trip(key: &JwtKey) -> anyhow::Result<Claims> {
+        let claims = Claims {
+            sub: "ana".to_string(),
+            exp: chrono::Utc::now().timestamp() + 60,
+        };
+        let token = encode(&Header::new(key.algorithm), &claims, key.encoding_key()?)?;
+        Ok(decode::<Claims>(&token, &key.decoding_key, &Validation::new(key.algorithm))?.claims)
+    }
+
+    #[test]
+    fn signs_and_verifies_with_each_supported_algorithm() {
+        let keys = [
+            JwtKey::new(
+                "hs".to_string(),
+                "HS256",
+                KeyMaterial::Secret(b"test-secret".to_vec()),
+            )
+            .unwrap(),
+            JwtKey::new(
+                "rs".to_string(),
+                "RS256",
+                pem(Some(RSA_PRIVATE_KEY), RSA_PUBLIC_KEY),
+            )
+            .unwrap(),
+            JwtKey::new(
+                "ed".to_string(),
+                "EdDSA",
+                pem(Some(ED_PRIVATE_KEY), ED_PUBLIC_KEY),
+            )
+            .unwrap(),
+        ];
+
+        for key in &keys {
+            assert_eq!(round_trip(key).unwrap().sub, "ana");
+        }
+    }
+
+    #[test]
+    fn rejects_key_material_that_does_not_match_the_algorithm() {
+        let secret_for_rsa = JwtKey::new(
+            "rs".to_string(),
+            "RS256",
+            KeyMaterial::Secret(b"test-secret".to_vec()),
+        );
+        let pem_for_hmac = JwtKey::new("hs".to_string(), "HS256", pem(None, RSA_PUBLIC_KEY));
+        let ed_key_for_rsa = JwtKey::new("rs".to_string(), "RS256", pem(None, ED_PUBLIC_KEY));
+        let unsupported = JwtKey::new("es".to_string(), "ES256", pem(None, ED_PUBLIC_KEY));
+
+        assert!(secret_for_rsa
+            .err()
+            .is_some_and(|e| e.to_string() == "Unsupported key rs for RS256"));
+        assert!(pem_for_hmac.is_err());
+        assert!(ed_key_for_rsa.is_err());
+        assert!(unsupported.is_err());
+    }
+
+    #[test]
+    fn rejects_a_private_key_from_another_pair() {
+        let mismatched = JwtKey::new(
+            "rs".to_string(),
+            "RS256",
+            pem(Some(RSA_PRIVATE_KEY), OTHER_RSA_PUBLIC_KEY),
+        );
+
+        assert!(mismatched
+            .err()
+            .is_some_and(|e| e.to_string().contains("don't belong together")));
+    }
+
+    #[test]
+    fn verify_only_keys_refuse_to_sign() {
+        let key = JwtKey::new("rs".to_string(), "RS256", pem(None, RSA_PUBLIC_KEY)).unwrap();
+
+        assert!(key.encoding_key().is_err());
+    }
+
+    fn keyring(active_kid: &str, keys: Vec<JwtKey>) -> Keyring {
+        Keyring {
+            active_kid: active_kid.to_string(),
+            keys: keys.into_iter().map(|key| (key.kid.clone(), key)).collect(),
+        }
+    }
+
+    #[test]
+    fn publishes_public_keys_but_never_secrets() {
+        let keyring = keyring(
+            "rs",
+            vec![
+                JwtKey::new(
+                    "rs".to_string(),
+                    "RS256",
+                    pem(Some(RSA_PRIVATE_KEY), RSA_PUBLIC_KEY),
+                )
+                .unwrap(),
+                JwtKey::new("ed".to_string(), "EdDSA", pem(None, ED_PUBLIC_KEY)).unwrap(),
+                JwtKey::new(
+                    "hs".to_string(),
+                    "HS256",
+                    KeyMaterial::Secret(b"test-secret".to_vec()),
+                )
+                .unwrap(),
+            ],
+        );
+
+        let json = serde_json::to_value(keyring.jwk_set()).unwrap();
+        let mut keys = json["keys"].as_array().unwrap().clone();
+        keys.sort_by_key(|key| key["kid"].as_str().unwrap().to_string());
+
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0]["kid"], "ed");
+        assert_eq!(keys[0]["alg"], "EdDSA");
+        assert_eq!(keys[0]["use"], "sig");
+        assert_eq!(keys[0]["kty"], "OKP");
+        assert_eq!(keys[0]["crv"], "Ed25519");
+        assert_eq!(keys[0]["x"], "uvvjMMgTdLwjlOGKWCkQ866jlPzPsbXBNMs4TnEY5FQ");
+        assert_eq!(keys[1]["kid"], "rs");
+        assert_eq!(keys[1]["alg"], "RS256");
+        assert_eq!(keys[1]["use"], "sig");
+        assert_eq!(keys[1]["kty"], "RSA");
+        assert_eq!(keys[1]["e"], "AQAB");
+        assert!(!json.to_string().contains("test-secret"));
+    }
+
+    #[test]
+    fn only_offers_published_keys_for_tokens_others_verify() {
+        let hmac = keyring(
+            "hs",
+            vec![JwtKey::new(
+                "hs".to_string(),
+                "HS256",
+                KeyMaterial::Secret(b"test-secret".to_vec()),
+            )
+            .unwrap()],
+        );
+        let ed = keyring(
+            "ed",
+            vec![JwtKey::new(
+                "ed".to_string(),
+                "EdDSA",
+                pem(Some(ED_PRIVATE_KEY), ED_PUBLIC_KEY),
+            )
+            .unwrap()],
+        );
+
+        assert!(hmac.public_signing_key().is_none());
+        assert!(ed.public_signing_key().is_some_and(|key| key.kid == "ed"));
+    }
+
+    #[test]
+    fn published_rsa_key_verifies_our_tokens() {
+        let key = JwtKey::new(
+            "rs".to_string(),
+            "RS256",
+            pem(Some(RSA_PRIVATE_KEY), RSA_PUBLIC_KEY),
+        )
+        .unwrap();
+        let claims = Claims {
+            sub: "ana".to_string(),
+            exp: chrono::Utc::now().timestamp() + 60,
+        };
+        let token = encode(
+            &Header::new(key.algorithm),
+            &claims,
+            key.encoding_key().unwrap(),
+        )
+        .unwrap();
+
+        let jwk = keyring("rs", vec![key]).jwk_set().keys.remove(0);
+        let decoding_key = DecodingKey::from_jwk(&jwk).unwrap();
+
+        assert!(
+            decode::<Claims>(&token, &decoding_key, &Validation::new(Algorithm::RS256)).is_ok()
+        );
+    }
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn keyring_config(active_kid: &str, retired_key_signs: bool) -> KeyringConfig {
+        let retired_private_key = retired_key_signs.then(|| fixture("rs256.pem"));
+        serde_json::from_value(serde_json::json!({
+            "activeKid": active_kid,
+            "keys": [
+                {
+                    "kid": "2026-10",
+                    "algorithm": "EdDSA",
+                    "privateKeyPath": fixture("eddsa.pem"),
+                    "publicKeyPath": fixture("eddsa.pub.pem"),
+                },
+                {
+                    "kid": "2026-04",
+                    "algorithm": "RS256",
+                    "privateKeyPath": retired_private_key,
+                    "publicKeyPath": fixture("rs256.pub.pem"),
+                },
+            ],
+        }))
+        .unwrap()
+    }
+
+    fn sign(key: &JwtKey, kid: Option<&str>) -> String {
+        let mut header = Header::new(key.algorithm);
+        header.kid = kid.map(|kid| kid.to_string());
+        let claims = Claims {
+            sub: "ana".to_string(),
+            exp: chrono::Utc::now().timestamp() + 60,
+        };
+        encode(&header, &claims, key.encoding_key().unwrap()).unwrap()
+    }
+
+    fn verifies(keyring: &Keyring, token: &str) -> bool {
+        let kid = jsonwebtoken::decode_header(token).unwrap().kid;
+        keyring.verification_key(kid.as_deref()).is_some_and(|key| {
+            decode::<Claims>(token, &key.decoding_key, &Validation::new(key.algorithm)).is_ok()
+        })
+    }
+
+    #[test]
+    fn tokens_of_retired_keys_keep_verifying_after_rotation() {
+        let retired_key = JwtKey::new(
+            "2026-04".to_string(),
+            "RS256",
+            pem(Some(RSA_PRIVATE_KEY), RSA_PUBLIC_KEY),
+        )
+        .unwrap();
+        let issued_before_rotation = sign(&retired_key, Some("2026-04"));
+
+        let keyring = Keyring::from_config(keyring_config("2026-10", false)).unwrap();
+
+        assert_eq!(keyring.signing_key().kid, "2026-10");
+        assert!(keyring.signing_key().encoding_key().is_ok());
+        assert!(keyring
+            .verification_key(Some("2026-04"))
+            .is_some_and(|key| key.encoding_key().is_err()));
+        assert!(verifies(&keyring, &issued_before_rotation));
+        assert!(verifies(
+            &keyring,
+            &sign(keyring.signing_key(), Some("2026-10"))
+        ));
+        assert!(keyring.verification_key(Some("2025-10")).is_none());
+    }
+
+    #[test]
+    fn tokens_without_kid_are_checked_against_the_active_key() {
+        let keyring = Keyring::from_config(keyring_config("2026-10", false)).unwrap();
+        let retired_key = JwtKey::new(
+            "2026-04".to_string(),
+            "RS256",
+            pem(Some(RSA_PRIVATE_KEY), RSA_PUBLIC_KEY),
+        )
+        .unwrap();
+
+        assert!(verifies(&keyring, &sign(keyring.signing_key(), None)));
+        assert!(!verifies(&keyring, &sign(&retired_key, None)));
+    }
+
+    #[test]
+    fn refuses_an_active_key_that_cannot_sign() {
+        let verify_only = Keyring::from_config(keyring_config("2026-04", false));
+        let unknown = Keyring::from_config(keyring_config("2027-04", true));
+
+        assert!(verify_only
+            .err()
+            .is_some_and(|e| e.to_string() == "Key 2026-04 can only verify tokens"));
+        assert!(unknown
+            .err()
+            .is_some_and(|e| e.to_string() == "Active key 2027-04 is not in the keyring"));
+        assert!(Keyring::from_config(keyring_config("2026-04", true)).is_ok());
+    }
+}
@@ -0,0 +1,119 @@
+use std::env;
+
+use chrono::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockoutPolicy {
+    pub threshold: i32,
+    pub backoff_base_seconds: i64,
+    pub lockout_seconds: i64,
+    pub max_lockout_seconds: i64,
+    pub reset_after_seconds: i64,
+}
+
+impl LockoutPolicy {
+    pub fn from_env(threshold_var: &str) -> Self {
+        Self {
+            threshold: env_number(threshold_var) as i32,
+            backoff_base_seconds: env_number("LOGIN_BACKOFF_BASE_SEC"),
+            lockout_seconds: env_number("LOGIN_LOCKOUT_SEC"),
+            max_lockout_seconds: env_number("LOGIN_MAX_LOCKOUT_SEC"),
+            reset_after_seconds: env_number("LOGIN_ATTEMPTS_RESET_SEC"),
+        }
+    }
+
+    /// Failures below the threshold only add a short, doubling delay; from the
+    /// threshold on the key is locked out, doubling up to the configured maximum.
+    pub fn delay_after(&self, failed_count: i32) -> Duration {
+        let seconds = if failed_count < self.threshold {
+            exponential(self.backoff_base_seconds, failed_count - 1)
+        } else {
+            exponential(self.lockout_seconds, failed_count - self.threshold)
+        };
+        Duration::seconds(seconds.min(self.max_lockout_seconds))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AttemptKey {
+    pub key: String,
+    pub policy: LockoutPolicy,
+}
+
+impl AttemptKey {
+    pub fn account(email: &str) -> Self {
+        Self {
+            key: format!("email:{}", email.trim().to_lowercase()),
+            policy: LockoutPolicy::from_env("LOGIN_ACCOUNT_THRESHOLD"),
+        }
+    }
+
+    pub fn ip(ip_address: &str) -> Self {
+        Self {
+            key: format!("ip:{}", ip_address),
+            policy: LockoutPolicy::from_env("LOGIN_IP_THRESHOLD"),
+        }
+    }
+}
+
+pub fn attempt_keys(email: &str, ip_address: Option<&str>) -> Vec<AttemptKey> {
+    let mut keys = vec![AttemptKey::account(email)];
+    if let Some(ip_address) = ip_address {
+        keys.push(AttemptKey::ip(ip_address));
+    }
+    keys
+}
+
+/// The account counter a successful first factor may reset. While a second factor
+/// is still to come that counter also guards its codes, so it is only reset once
+/// the second factor has passed too.
+pub fn cleared_by_first_factor(
+    attempt_keys: &[AttemptKey],
+    second_factor_pending: bool,
+) -> Option<&AttemptKey> {
+    attempt_keys.first().filter(|_| !second_factor_pending)
+}
+
+fn exponential(base: i64, exponent: i32) -> i64 {
+    base.saturating_mul(2_i64.saturating_pow(exponent.clamp(0, 32) as u32))
+}
+
+fn env_number(name: &str) -> i64 {
+    env::var(name)
+        .unwrap_or_else(|_| panic!("{} must be set.", name))
+        .parse()
+        .unwrap_or_else(|_| panic!("{} must be a number.", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> LockoutPolicy {
+        LockoutPolicy {
+            threshold: 5,
+            backoff_base_seconds: 1,
+            lockout_seconds: 900,
+            max_lockout_seconds: 3600,
+            reset_after_seconds: 86400,
+        }
+    }
+
+    #[test]
+    fn doubles_the_delay_before_the_threshold() {
+        let policy = policy();
+
+        assert_eq!(policy.delay_after(1), Duration::seconds(1));
+        assert_eq!(policy.delay_after(2), Duration::seconds(2));
+        assert_eq!(policy.delay_after(4), Duration::seconds(8));
+    }
+
+    #[test]
+    fn locks_out_from_the_threshold_up_to_the_maximum() {
+        let policy = policy();
+
+        assert_eq!(policy.delay_after(5), Duration::seconds(900));
+        assert_eq!(policy.delay_after(6), Duration::seconds(1800));
+        assert_eq!(policy.delay_after(50), Duration::seconds(3600));
+    }
+}
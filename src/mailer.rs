@@ -0,0 +1,106 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: &Email) -> anyhow::Result<()>;
+}
+
+/// Writes every message to the application log. Only meant for local development.
+pub struct LogMailer;
+
+impl Mailer for LogMailer {
+    fn send(&self, email: &Email) -> anyhow::Result<()> {
+        log::info!("Mail to {}: {}\n{}", email.to, email.subject, email.body);
+        Ok(())
+    }
+}
+
+/// Drops every message as a `.eml` file in `directory`, so links can be opened
+/// locally without an SMTP server.
+pub struct FileMailer {
+    pub directory: PathBuf,
+    pub from: String,
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, email: &Email) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.directory)
+            .with_context(|| format!("Couldn't create {}", self.directory.display()))?;
+        let path = self.directory.join(format!(
+            "{}-{}.eml",
+            chrono::Utc::now().format("%Y%m%dT%H%M%S"),
+            Uuid::new_v4()
+        ));
+        let message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\n\r\n{}\r\n",
+            self.from, email.to, email.subject, email.body
+        );
+        fs::write(&path, message).with_context(|| format!("Couldn't write {}", path.display()))
+    }
+}
+
+pub fn from_env() -> anyhow::Result<Arc<dyn Mailer>> {
+    let mailer = env::var("MAILER").unwrap_or_else(|_| "log".to_string());
+    match mailer.as_str() {
+        "log" => Ok(Arc::new(LogMailer)),
+        "file" => Ok(Arc::new(FileMailer {
+            directory: env::var("MAILER_DIR")
+                .context("MAILER_DIR must be set")?
+                .into(),
+            from: env::var("MAIL_FROM").context("MAIL_FROM must be set")?,
+        })),
+        _ => bail!("MAILER must be log or file"),
+    }
+}
+
+/// Link into the frontend, which posts the token back to the API.
+pub fn app_link(path: &str, token: &str) -> anyhow::Result<String> {
+    let base_url = env::var("APP_BASE_URL").context("APP_BASE_URL must be set")?;
+    Ok(format!(
+        "{}{}?token={}",
+        base_url.trim_end_matches('/'),
+        path,
+        token
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_mailer_writes_one_message_per_email() {
+        let directory = env::temp_dir().join(format!("rl-mail-{}", Uuid::new_v4()));
+        let mailer = FileMailer {
+            directory: directory.clone(),
+            from: "no-reply@rl.com".to_string(),
+        };
+
+        mailer
+            .send(&Email {
+                to: "ana@rl.com".to_string(),
+                subject: "Verify your email".to_string(),
+                body: "https://app.rl.com/verify-email?token=abc".to_string(),
+            })
+            .unwrap();
+
+        let files = fs::read_dir(&directory).unwrap().collect::<Vec<_>>();
+        assert_eq!(files.len(), 1);
+        let message = fs::read_to_string(files[0].as_ref().unwrap().path()).unwrap();
+        assert!(message.starts_with("From: no-reply@rl.com\r\nTo: ana@rl.com\r\n"));
+        assert!(message.contains("verify-email?token=abc"));
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -1,4 +1,5 @@
 use std::env;
+use std::sync::Arc;
 
 use actix_web::{App, get, HttpResponse, HttpServer, Responder, web};
 use actix_web::middleware::Logger;
@@ -10,10 +11,20 @@ use env_logger::Env;
 use r2d2::Pool;
 
 mod auth;
+mod client_ip;
 mod db;
+mod keys;
+mod lockout;
+mod mailer;
+mod mfa;
 mod model;
+mod oauth;
+mod one_time_token;
+mod password;
+mod rate_limit;
 mod schema;
 mod users;
+mod webauthn;
 
 pub type DbPool = Pool<ConnectionManager<PgConnection>>;
 
@@ -28,28 +39,147 @@ async fn main() -> std::io::Result<()> {
         .build(manager)
         .expect("Failed to create pool.");
 
-    let auth_validator_func = move |req, credentials| async {
-        let auth_validator = auth::AuthValidator::new("USER".to_string());
-        auth_validator.validator(req, credentials)
+    keys::keyring();
+    client_ip::trusted_proxies();
+    password::hashing();
+    password::policy();
+    webauthn::relying_party();
+    let mailer = mailer::from_env().expect("Mailer must be configured.");
+    #[cfg(unix)]
+    actix_web::rt::spawn(keys::reload_on_hangup());
+
+    let connection_check: Arc<dyn auth::ConnectionCheck> =
+        Arc::new(auth::DbConnectionCheck::new(pool.clone()));
+    let user_connection_check = connection_check.clone();
+    let auth_validator_func = move |req, credentials| {
+        let auth_validator = auth::AuthValidator::new("USER".to_string(), user_connection_check.clone());
+        async move { auth_validator.validator(req, credentials).await }
     };
     let auth = HttpAuthentication::bearer(auth_validator_func);
+    let userinfo_validator_func = move |req, credentials| {
+        let auth_validator = auth::AuthValidator::scoped("openid".to_string(), connection_check.clone());
+        async move { auth_validator.validator(req, credentials).await }
+    };
+    let userinfo_auth = HttpAuthentication::bearer(userinfo_validator_func);
     let api_port = env::var("API_PORT").expect("API_PORT must be set.").parse().expect("API_PORT must be a number.");
     let api_host = env::var("API_HOST").expect("API_HOST must be set.");
+    let rate_limiter = rate_limit::RateLimiter::new(Arc::new(rate_limit::InMemoryStore::default()))
+        .limit(
+            "/api/v1/users/register",
+            rate_limit::RateLimitRule::from_env("RATE_LIMIT_REGISTER"),
+        )
+        .limit_by_email(
+            "/api/v1/users/login",
+            rate_limit::RateLimitRule::from_env("RATE_LIMIT_LOGIN"),
+        )
+        .limit(
+            "/api/v1/users/login/mfa",
+            rate_limit::RateLimitRule::from_env("RATE_LIMIT_LOGIN"),
+        )
+        .limit_by_email(
+            "/api/v1/users/login/code/request",
+            rate_limit::RateLimitRule::from_env("RATE_LIMIT_EMAIL"),
+        )
+        .limit_by_email(
+            "/api/v1/users/login/code/verify",
+            rate_limit::RateLimitRule::from_env("RATE_LIMIT_LOGIN"),
+        )
+        .limit(
+            "/api/v1/users/login/passkey/start",
+            rate_limit::RateLimitRule::from_env("RATE_LIMIT_LOGIN"),
+        )
+        .limit(
+            "/api/v1/users/login/passkey/finish",
+            rate_limit::RateLimitRule::from_env("RATE_LIMIT_LOGIN"),
+        )
+        .limit(
+            "/api/v1/users/token",
+            rate_limit::RateLimitRule::from_env("RATE_LIMIT_TOKEN"),
+        )
+        .limit(
+            "/oauth/token",
+            rate_limit::RateLimitRule::from_env("RATE_LIMIT_TOKEN"),
+        )
+        .limit_by_email(
+            "/api/v1/users/verify-email/resend",
+            rate_limit::RateLimitRule::from_env("RATE_LIMIT_EMAIL"),
+        )
+        .limit_by_email(
+            "/api/v1/users/password/forgot",
+            rate_limit::RateLimitRule::from_env("RATE_LIMIT_EMAIL"),
+        );
     HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
             .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::from(mailer.clone()))
             .service(health)
+            .service(jwks)
+            .service(oauth::openid_configuration)
+            .service(
+                web::scope("/userinfo")
+                    .wrap(userinfo_auth.clone())
+                    .service(oauth::userinfo),
+            )
             .service(
                 web::scope("/api/v1/index")
                     .wrap(auth.clone())
                     .service(index),
             )
+            .service(
+                web::scope("/oauth")
+                    .wrap(rate_limiter.clone())
+                    .service(oauth::authorize)
+                    .service(oauth::token)
+                    .service(oauth::introspect),
+            )
             .service(
                 web::scope("/api/v1")
+                    .wrap(rate_limiter.clone())
                     .service(users::register_user)
                     .service(users::refresh_auth)
-                    .service(users::login),
+                    .service(users::login)
+                    .service(users::login_mfa)
+                    .service(users::request_login_code)
+                    .service(users::verify_login_code)
+                    .service(users::start_passkey_login)
+                    .service(users::finish_passkey_login)
+                    .service(users::verify_email)
+                    .service(users::resend_verification_email)
+                    .service(users::forgot_password)
+                    .service(users::reset_password)
+                    .service(users::confirm_email_change)
+                    .service(users::cancel_email_change)
+                    .service(
+                        web::scope("/users/logout")
+                            .wrap(auth.clone())
+                            .service(users::logout),
+                    )
+                    .service(
+                        web::scope("/users/logout-all")
+                            .wrap(auth.clone())
+                            .service(users::logout_all),
+                    )
+                    .service(
+                        web::scope("/users/me")
+                            .wrap(auth.clone())
+                            .service(users::list_sessions)
+                            .service(users::revoke_session)
+                            .service(users::change_password)
+                            .service(users::change_email)
+                            .service(users::enroll_totp)
+                            .service(users::confirm_totp)
+                            .service(users::disable_totp)
+                            .service(users::start_passkey_registration)
+                            .service(users::finish_passkey_registration)
+                            .service(users::list_passkeys)
+                            .service(users::delete_passkey),
+                    )
+                    .service(
+                        web::scope("/oauth/authorize")
+                            .wrap(auth.clone())
+                            .service(oauth::approve_authorization),
+                    ),
             )
     })
     .bind((api_host, api_port))?
@@ -62,6 +192,11 @@ async fn index() -> impl Responder {
     "Hello World with Security!"
 }
 
+#[get("/.well-known/jwks.json")]
+async fn jwks() -> HttpResponse {
+    HttpResponse::Ok().json(keys::keyring().jwk_set())
+}
+
 #[get("/health")]
 async fn health() -> HttpResponse {
     HttpResponse::Ok().body("")
@@ -0,0 +1,131 @@
+use std::env;
+
+use anyhow::anyhow;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::one_time_token::hash_token;
+
+const TOTP_DIGITS: usize = 6;
+const TOTP_STEP_SECONDS: u64 = 30;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// A fresh 160-bit secret, base32 encoded as authenticator apps expect it.
+pub fn generate_secret() -> String {
+    Secret::generate_secret().to_encoded().to_string()
+}
+
+pub fn otpauth_uri(secret: &str, email: &str) -> anyhow::Result<String> {
+    Ok(totp(secret, email)?.get_url())
+}
+
+/// Returns the time step a valid code belongs to, allowing one step of clock drift
+/// either way. Steps up to `last_used_step` are refused so a code can't be replayed.
+pub fn verify_code(secret: &str, code: &str, last_used_step: Option<i64>, now: u64) -> Option<i64> {
+    let totp = totp(secret, "").ok()?;
+    let code = code.trim();
+    let current_step = (now / TOTP_STEP_SECONDS) as i64;
+    (current_step - 1..=current_step + 1)
+        .filter(|step| last_used_step.is_none_or(|last| *step > last))
+        .find(|step| totp.generate(*step as u64 * TOTP_STEP_SECONDS) == code)
+}
+
+/// Ten single-use codes like `k7qhm-2xw9t`, shown to the user once.
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = (0..10).map(|_| recovery_code_char()).collect::<String>();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/// Bytes from the top of the range that the alphabet doesn't divide evenly are
+/// drawn again, so every character is equally likely.
+fn recovery_code_char() -> char {
+    let alphabet_len = RECOVERY_CODE_ALPHABET.len() as u32;
+    let limit = 256 - 256 % alphabet_len;
+    loop {
+        let value = OsRng.next_u32() & 0xff;
+        if value < limit {
+            return RECOVERY_CODE_ALPHABET[(value % alphabet_len) as usize] as char;
+        }
+    }
+}
+
+/// Users retype recovery codes, so case, spaces and the dash don't matter.
+pub fn hash_recovery_code(code: &str) -> String {
+    hash_token(
+        &code
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_lowercase(),
+    )
+}
+
+fn totp(secret: &str, email: &str) -> anyhow::Result<TOTP> {
+    let issuer = env::var("TOTP_ISSUER").unwrap_or_else(|_| "RL".to_string());
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| anyhow!("Invalid TOTP secret: {:?}", e))?;
+    TOTP::new(
+        Algorithm::SHA1,
+        TOTP_DIGITS,
+        1,
+        TOTP_STEP_SECONDS,
+        secret,
+        Some(issuer),
+        email.to_string(),
+    )
+    .map_err(|e| anyhow!("Invalid TOTP parameters: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_codes_within_one_step_once() {
+        let secret = generate_secret();
+        let now = 1_800_000_000;
+        let code = totp(&secret, "").unwrap().generate(now - TOTP_STEP_SECONDS);
+        let step = (now / TOTP_STEP_SECONDS) as i64 - 1;
+
+        assert_eq!(verify_code(&secret, &code, None, now), Some(step));
+        assert_eq!(verify_code(&secret, &code, Some(step), now), None);
+        assert_eq!(
+            verify_code(&secret, &code, None, now + 2 * TOTP_STEP_SECONDS),
+            None
+        );
+    }
+
+    #[test]
+    fn builds_an_otpauth_uri_for_the_account() {
+        let secret = generate_secret();
+
+        let uri = otpauth_uri(&secret, "ana@rl.com").unwrap();
+
+        assert!(uri.starts_with("otpauth://totp/RL:ana%40rl.com?secret="));
+        assert!(uri.contains(&secret));
+    }
+
+    #[test]
+    fn recovery_codes_hash_the_same_however_they_are_typed() {
+        let codes = generate_recovery_codes();
+
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert!(codes.iter().all(|code| {
+            code.len() == 11
+                && code
+                    .bytes()
+                    .filter(|c| *c != b'-')
+                    .all(|c| RECOVERY_CODE_ALPHABET.contains(&c))
+        }));
+        assert_eq!(
+            hash_recovery_code(&codes[0]),
+            hash_recovery_code(&format!(" {} ", codes[0].to_uppercase().replace('-', "")))
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use chrono::NaiveDateTime;
-use diesel::{Insertable, Queryable, Selectable};
+use diesel::{AsChangeset, Insertable, Queryable, Selectable};
 use uuid::Uuid;
 
 use crate::schema::*;
@@ -13,6 +13,7 @@ pub struct RLUser {
     pub nickname: String,
     pub password: String,
     pub id_role: i32,
+    pub email_verified_at: Option<NaiveDateTime>,
 }
 
 #[derive(Debug, PartialEq, Queryable, Selectable)]
@@ -30,4 +31,192 @@ pub struct ConnectionModel {
     pub id_user: Uuid,
     pub connect_at: Option<NaiveDateTime>,
     pub ended_at: Option<NaiveDateTime>,
+    pub id_session: Uuid,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub device_label: Option<String>,
+    pub refreshed_at: Option<NaiveDateTime>,
+    pub rotated_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Queryable, Selectable, Insertable)]
+#[diesel(table_name = security_events)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct SecurityEvent {
+    pub id_security_event: Uuid,
+    pub id_user: Uuid,
+    pub event_type: String,
+    pub id_session: Option<Uuid>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Queryable, Selectable, Insertable, AsChangeset)]
+#[diesel(table_name = login_attempts)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct LoginAttempt {
+    pub attempt_key: String,
+    pub failed_count: i32,
+    pub last_failed_at: Option<NaiveDateTime>,
+    pub locked_until: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Queryable, Selectable, Insertable)]
+#[diesel(table_name = email_verifications)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct EmailVerification {
+    pub id_email_verification: Uuid,
+    pub id_user: Uuid,
+    pub email: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Queryable, Selectable, Insertable)]
+#[diesel(table_name = password_resets)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct PasswordReset {
+    pub id_password_reset: Uuid,
+    pub id_user: Uuid,
+    pub token_hash: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Queryable, Selectable, Insertable)]
+#[diesel(table_name = login_codes)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct LoginCode {
+    pub id_login_code: Uuid,
+    pub id_user: Uuid,
+    pub code_hash: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub failed_attempts: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Queryable, Selectable, Insertable)]
+#[diesel(table_name = email_changes)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct EmailChange {
+    pub id_email_change: Uuid,
+    pub id_user: Uuid,
+    pub old_email: String,
+    pub new_email: String,
+    pub token_hash: String,
+    pub cancel_token_hash: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub confirmed_at: Option<NaiveDateTime>,
+    pub cancelled_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Queryable, Selectable, Insertable)]
+#[diesel(table_name = user_totp)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct UserTotp {
+    pub id_user: Uuid,
+    pub secret: String,
+    pub created_at: NaiveDateTime,
+    pub confirmed_at: Option<NaiveDateTime>,
+    pub last_used_step: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Queryable, Selectable, Insertable)]
+#[diesel(table_name = recovery_codes)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct RecoveryCode {
+    pub id_recovery_code: Uuid,
+    pub id_user: Uuid,
+    pub code_hash: String,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Queryable, Selectable, Insertable)]
+#[diesel(table_name = webauthn_credentials)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct WebauthnCredential {
+    pub id_webauthn_credential: Uuid,
+    pub id_user: Uuid,
+    pub credential_id: String,
+    pub public_key: Vec<u8>,
+    pub sign_count: i64,
+    pub label: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Queryable, Selectable, Insertable)]
+#[diesel(table_name = webauthn_challenges)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct WebauthnChallenge {
+    pub id_webauthn_challenge: Uuid,
+    pub id_user: Option<Uuid>,
+    pub challenge: String,
+    pub ceremony: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+/// A client allowed to request tokens through the OAuth endpoints. Confidential
+/// clients store the SHA-256 hash of a generated secret, public ones (SPAs, mobile
+/// apps) have none and must always use PKCE.
+#[derive(Debug, Clone, PartialEq, Queryable, Selectable, Insertable)]
+#[diesel(table_name = oauth_clients)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct OAuthClient {
+    pub id_oauth_client: Uuid,
+    pub client_id: String,
+    /// `hash_token` of the secret. Secrets must be issued like our other tokens,
+    /// with `generate_token`'s 256 random bits, which is what makes a fast unsalted
+    /// hash enough; a chosen password would need the password hasher.
+    pub client_secret_hash: Option<String>,
+    pub name: String,
+    pub redirect_uris: Vec<Option<String>>,
+    pub grant_types: Vec<Option<String>>,
+    pub scopes: Vec<Option<String>>,
+    pub first_party: bool,
+    pub created_at: NaiveDateTime,
+}
+
+impl OAuthClient {
+    /// Redirect URIs must match one on the allow-list exactly.
+    pub fn allows_redirect_uri(&self, redirect_uri: &str) -> bool {
+        self.redirect_uris
+            .iter()
+            .flatten()
+            .any(|uri| uri == redirect_uri)
+    }
+
+    pub fn allows_grant_type(&self, grant_type: &str) -> bool {
+        self.grant_types
+            .iter()
+            .flatten()
+            .any(|grant| grant == grant_type)
+    }
+
+    pub fn allowed_scopes(&self) -> Vec<&str> {
+        self.scopes.iter().flatten().map(String::as_str).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Queryable, Selectable, Insertable)]
+#[diesel(table_name = oauth_authorization_codes)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct OAuthAuthorizationCode {
+    pub id_oauth_authorization_code: Uuid,
+    pub code_hash: String,
+    pub id_oauth_client: Uuid,
+    pub id_user: Uuid,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub code_challenge: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub nonce: Option<String>,
 }
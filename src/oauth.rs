@@ -0,0 +1,889 @@
+use std::env;
+
+use actix_web::body::BoxBody;
+use actix_web::error::BlockingError;
+use actix_web::http::header::{CACHE_CONTROL, LOCATION, PRAGMA, WWW_AUTHENTICATE};
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, HttpRequest, HttpResponse, ResponseError};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use anyhow::{anyhow, Context};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use url::Url;
+use uuid::Uuid;
+
+use crate::auth::TokenClaims;
+use crate::model::{OAuthClient, RLUser};
+use crate::one_time_token::{generate_token, hash_token};
+use crate::users::{ClientInfo, UserError};
+use crate::{auth, db, keys, DbPool};
+
+pub const AUTHORIZATION_CODE: &str = "authorization_code";
+pub const REFRESH_TOKEN: &str = "refresh_token";
+pub const CLIENT_CREDENTIALS: &str = "client_credentials";
+
+/// Errors of the OAuth endpoints, answered in the RFC 6749 format client libraries
+/// expect instead of `UserErrorResponse`.
+#[derive(thiserror::Error, Debug)]
+pub enum OAuthError {
+    #[error("{0}")]
+    InvalidRequest(String),
+    #[error("Client authentication failed.")]
+    InvalidClient,
+    #[error("{0}")]
+    InvalidGrant(String),
+    #[error("The client is not allowed to use this grant type.")]
+    UnauthorizedClient,
+    #[error("The grant type is not supported.")]
+    UnsupportedGrantType,
+    #[error("Only the code response type is supported.")]
+    UnsupportedResponseType,
+    #[error("The requested scope is not allowed for this client.")]
+    InvalidScope,
+    #[error(transparent)]
+    User(#[from] UserError),
+}
+
+impl From<BlockingError> for OAuthError {
+    fn from(value: BlockingError) -> Self {
+        OAuthError::User(UserError::from(value))
+    }
+}
+
+impl From<anyhow::Error> for OAuthError {
+    fn from(value: anyhow::Error) -> Self {
+        OAuthError::User(UserError::from(value))
+    }
+}
+
+impl From<diesel::result::Error> for OAuthError {
+    fn from(value: diesel::result::Error) -> Self {
+        OAuthError::User(UserError::from(value))
+    }
+}
+
+impl OAuthError {
+    fn error_code(&self) -> &'static str {
+        match self {
+            OAuthError::InvalidRequest(_) => "invalid_request",
+            OAuthError::InvalidClient => "invalid_client",
+            OAuthError::InvalidGrant(_) => "invalid_grant",
+            OAuthError::UnauthorizedClient => "unauthorized_client",
+            OAuthError::UnsupportedGrantType => "unsupported_grant_type",
+            OAuthError::UnsupportedResponseType => "unsupported_response_type",
+            OAuthError::InvalidScope => "invalid_scope",
+            OAuthError::User(e) if is_grant_error(e) => "invalid_grant",
+            OAuthError::User(_) => "server_error",
+        }
+    }
+}
+
+/// Errors about the presented code or refresh token. Anything else, like a
+/// lockout or a database failure, keeps its usual response.
+fn is_grant_error(error: &UserError) -> bool {
+    matches!(
+        error,
+        UserError::InvalidToken
+            | UserError::ExpiredToken
+            | UserError::WrongTokenKind
+            | UserError::RefreshTokenReused
+            | UserError::UserNotFound
+    )
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OAuthErrorResponse {
+    pub error: String,
+    pub error_description: String,
+}
+
+impl ResponseError for OAuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            OAuthError::InvalidClient => StatusCode::UNAUTHORIZED,
+            OAuthError::User(e) if !is_grant_error(e) => e.status_code(),
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        if let OAuthError::User(e) = self {
+            if !is_grant_error(e) {
+                return e.error_response();
+            }
+        }
+        let mut response = HttpResponse::build(self.status_code());
+        response.insert_header((CACHE_CONTROL, "no-store"));
+        if let OAuthError::InvalidClient = self {
+            response.insert_header((WWW_AUTHENTICATE, "Basic"));
+        }
+        response.json(OAuthErrorResponse {
+            error: self.error_code().to_string(),
+            error_description: self.to_string(),
+        })
+    }
+}
+
+/// The query of `/oauth/authorize`, forwarded as is by the frontend once the user
+/// has signed in and agreed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthorizationRequest {
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub scope: Option<String>,
+    pub state: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+    /// OpenID Connect: echoed in the ID token so the client can tie it to this request.
+    pub nonce: Option<String>,
+}
+
+/// Checks what the user is about to agree to and returns the scope that will be
+/// granted. Unknown clients and redirect URIs are reported the same way.
+fn validate_authorization_request(
+    client: Option<OAuthClient>,
+    request: &AuthorizationRequest,
+) -> Result<(OAuthClient, String), OAuthError> {
+    let client = client
+        .filter(|client| client.allows_redirect_uri(&request.redirect_uri))
+        .ok_or_else(|| OAuthError::InvalidRequest("Unknown client or redirect URI.".to_string()))?;
+    if request.response_type != "code" {
+        return Err(OAuthError::UnsupportedResponseType);
+    }
+    if !client.allows_grant_type(AUTHORIZATION_CODE) {
+        return Err(OAuthError::UnauthorizedClient);
+    }
+    if request
+        .nonce
+        .as_ref()
+        .is_some_and(|nonce| nonce.len() > 255)
+    {
+        return Err(OAuthError::InvalidRequest("nonce is too long.".to_string()));
+    }
+    let valid_challenge = request
+        .code_challenge
+        .as_ref()
+        .is_some_and(|challenge| (43..=128).contains(&challenge.len()));
+    if !valid_challenge || request.code_challenge_method.as_deref() != Some("S256") {
+        return Err(OAuthError::InvalidRequest(
+            "PKCE with the S256 method is required.".to_string(),
+        ));
+    }
+    let scope = granted_scope(
+        &client,
+        request.scope.as_deref(),
+        auth::id_tokens_supported(),
+    )?;
+    Ok((client, scope))
+}
+
+/// Requested scopes must all be allowed for the client; without a request the
+/// client gets all of them. `openid` is only available while we can sign ID tokens.
+fn granted_scope(
+    client: &OAuthClient,
+    requested: Option<&str>,
+    openid_supported: bool,
+) -> Result<String, OAuthError> {
+    let allowed = client
+        .allowed_scopes()
+        .into_iter()
+        .filter(|scope| openid_supported || *scope != "openid")
+        .collect::<Vec<_>>();
+    match requested.map(str::split_whitespace) {
+        Some(requested) => {
+            let mut scopes = Vec::new();
+            for scope in requested {
+                if !allowed.contains(&scope) {
+                    return Err(OAuthError::InvalidScope);
+                }
+                if !scopes.contains(&scope) {
+                    scopes.push(scope);
+                }
+            }
+            Ok(scopes.join(" "))
+        }
+        None => Ok(allowed.join(" ")),
+    }
+}
+
+/// RFC 7636 S256: the challenge is the unpadded base64url SHA-256 of the verifier.
+fn verify_code_challenge(code_verifier: &str, code_challenge: &str) -> bool {
+    (43..=128).contains(&code_verifier.len())
+        && URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes())) == code_challenge
+}
+
+/// Entry point for the browser. This service has no pages of its own, so after a
+/// first look at the client it hands over to the frontend's sign-in and consent
+/// screen, which calls `/api/v1/oauth/authorize`.
+#[get("/authorize")]
+pub async fn authorize(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    request: web::Query<AuthorizationRequest>,
+) -> Result<HttpResponse, OAuthError> {
+    let request = request.into_inner();
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    web::block(move || {
+        let client = db::get_oauth_client(&mut conn, &request.client_id)?;
+        validate_authorization_request(client, &request)
+    })
+    .await??;
+
+    let base_url = env::var("APP_BASE_URL").context("APP_BASE_URL must be set")?;
+    Ok(HttpResponse::Found()
+        .insert_header((
+            LOCATION,
+            format!(
+                "{}/oauth/authorize?{}",
+                base_url.trim_end_matches('/'),
+                req.query_string()
+            ),
+        ))
+        .finish())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthorizationResponse {
+    pub redirect_uri: String,
+}
+
+/// Called by the frontend with the user's own access token once they agreed.
+/// Returns where to send the browser, with the code attached.
+#[post("")]
+pub async fn approve_authorization(
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<TokenClaims>,
+    request: web::Json<AuthorizationRequest>,
+) -> Result<HttpResponse, OAuthError> {
+    let user_id = approving_user(&claims)?;
+    let request = request.into_inner();
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    let redirect_uri = web::block(move || {
+        let client = db::get_oauth_client(&mut conn, &request.client_id)?;
+        let (client, scope) = validate_authorization_request(client, &request)?;
+        let code = generate_token();
+        db::create_authorization_code(
+            &mut conn,
+            &client,
+            user_id,
+            hash_token(&code),
+            &request,
+            &scope,
+        )?;
+
+        let mut redirect_uri = Url::parse(&request.redirect_uri)
+            .map_err(|_| OAuthError::InvalidRequest("Invalid redirect URI.".to_string()))?;
+        redirect_uri.query_pairs_mut().append_pair("code", &code);
+        if let Some(state) = &request.state {
+            redirect_uri.query_pairs_mut().append_pair("state", state);
+        }
+        Ok::<_, OAuthError>(redirect_uri.to_string())
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(AuthorizationResponse { redirect_uri }))
+}
+
+/// Only the user's own session may approve. A token issued to an OAuth client,
+/// even a first-party one, could otherwise mint codes for any other client.
+fn approving_user(claims: &TokenClaims) -> Result<Uuid, UserError> {
+    if claims.client_id.is_some() {
+        return Err(UserError::Forbidden);
+    }
+    claims.user_uuid()
+}
+
+/// The form of `/oauth/token`. Clients authenticate with HTTP Basic or, as RFC
+/// 6749 also allows, `client_id` and `client_secret` in the body.
+#[derive(Debug, Deserialize)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub code_verifier: Option<String>,
+    pub refresh_token: Option<String>,
+    pub scope: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OAuthTokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    pub scope: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+/// Public clients have no secret and must not send one.
+fn authenticate_client(
+    conn: &mut diesel::PgConnection,
+    client_id: Option<&str>,
+    client_secret: Option<&str>,
+) -> Result<OAuthClient, OAuthError> {
+    let client = db::get_oauth_client(conn, client_id.ok_or(OAuthError::InvalidClient)?)?
+        .ok_or(OAuthError::InvalidClient)?;
+    let authenticated = match (&client.client_secret_hash, client_secret) {
+        (Some(secret_hash), Some(secret)) => client_secret_matches(secret_hash, secret),
+        (None, None) => true,
+        _ => false,
+    };
+    if !authenticated {
+        return Err(OAuthError::InvalidClient);
+    }
+    Ok(client)
+}
+
+/// Compared in constant time so response times don't reveal how much of the hash
+/// matched.
+fn client_secret_matches(secret_hash: &str, secret: &str) -> bool {
+    secret_hash
+        .as_bytes()
+        .ct_eq(hash_token(secret).as_bytes())
+        .into()
+}
+
+/// Third-party clients get tokens without roles, so they can't call the user API
+/// on the user's behalf, only services that check the granted scope. An ID token
+/// comes along whenever `openid` was granted.
+fn issue_tokens(
+    client: &OAuthClient,
+    user: &RLUser,
+    connection_id: Uuid,
+    role: String,
+    scope: String,
+    nonce: Option<&str>,
+) -> Result<OAuthTokenResponse, OAuthError> {
+    let roles = if client.first_party {
+        vec![role]
+    } else {
+        vec![]
+    };
+    let (access_token, refresh_token, expires_in) = auth::generate_oauth_tokens(
+        user.id_user,
+        connection_id,
+        roles,
+        &client.client_id,
+        &scope,
+    )?;
+    let id_token = auth::has_scope(&scope, "openid")
+        .then(|| auth::generate_id_token(user, &client.client_id, &scope, nonce))
+        .transpose()?;
+    Ok(OAuthTokenResponse {
+        access_token,
+        token_type: "Bearer".to_string(),
+        expires_in,
+        refresh_token: Some(refresh_token),
+        scope,
+        id_token,
+    })
+}
+
+fn required<'a>(value: &'a Option<String>, name: &str) -> Result<&'a str, OAuthError> {
+    value
+        .as_deref()
+        .ok_or_else(|| OAuthError::InvalidRequest(format!("{} is required.", name)))
+}
+
+#[post("/token")]
+pub async fn token(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    basic: Option<BasicAuth>,
+    request: web::Form<TokenRequest>,
+) -> Result<HttpResponse, OAuthError> {
+    let request = request.into_inner();
+    let client_info = ClientInfo::from_request(&req, None);
+    let (client_id, client_secret) = match basic {
+        Some(basic) => (
+            Some(basic.user_id().to_string()),
+            basic.password().map(|password| password.to_string()),
+        ),
+        None => (request.client_id.clone(), request.client_secret.clone()),
+    };
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    let response = web::block(move || {
+        let client =
+            authenticate_client(&mut conn, client_id.as_deref(), client_secret.as_deref())?;
+        if !client.allows_grant_type(&request.grant_type) {
+            return Err(match request.grant_type.as_str() {
+                AUTHORIZATION_CODE | REFRESH_TOKEN | CLIENT_CREDENTIALS => {
+                    OAuthError::UnauthorizedClient
+                }
+                _ => OAuthError::UnsupportedGrantType,
+            });
+        }
+        let client_info = ClientInfo {
+            device_label: Some(client.name.clone()),
+            ..client_info
+        };
+
+        match request.grant_type.as_str() {
+            AUTHORIZATION_CODE => {
+                let code = required(&request.code, "code")?;
+                let authorization = db::use_authorization_code(&mut conn, &hash_token(code))?;
+                let valid = authorization.id_oauth_client == client.id_oauth_client
+                    && request.redirect_uri.as_deref() == Some(&authorization.redirect_uri)
+                    && request
+                        .code_verifier
+                        .as_deref()
+                        .is_some_and(|code_verifier| {
+                            verify_code_challenge(code_verifier, &authorization.code_challenge)
+                        });
+                if !valid {
+                    return Err(OAuthError::InvalidGrant(
+                        "The authorization code is invalid.".to_string(),
+                    ));
+                }
+                let user = db::get_user(&mut conn, authorization.id_user)?;
+                let role = db::get_role_by_user_id(&mut conn, user.id_user.to_string())?;
+                let connection_id =
+                    db::generate_new_connection(&mut conn, user.id_user, client_info)?;
+                issue_tokens(
+                    &client,
+                    &user,
+                    connection_id,
+                    role.description,
+                    authorization.scope,
+                    authorization.nonce.as_deref(),
+                )
+            }
+            REFRESH_TOKEN => {
+                let refresh_token = required(&request.refresh_token, "refresh_token")?;
+                let claims = auth::get_claims_and_validate(
+                    refresh_token.to_string(),
+                    auth::TokenKind::Refresh,
+                )
+                .ok()
+                .filter(|claims| claims.client_id.as_deref() == Some(&client.client_id))
+                .ok_or_else(|| {
+                    OAuthError::InvalidGrant("The refresh token is invalid.".to_string())
+                })?;
+                let user = db::get_user(&mut conn, claims.user_uuid()?)?;
+                let role = db::get_role_by_user_id(&mut conn, claims.user_id.clone())?;
+                let connection_id = db::rotate_connection(
+                    &mut conn,
+                    user.id_user,
+                    claims.connection_uuid()?,
+                    client_info,
+                )?;
+                issue_tokens(
+                    &client,
+                    &user,
+                    connection_id,
+                    role.description,
+                    claims.scope.unwrap_or_default(),
+                    None,
+                )
+            }
+            CLIENT_CREDENTIALS => {
+                if client.client_secret_hash.is_none() {
+                    return Err(OAuthError::UnauthorizedClient);
+                }
+                let scope = granted_scope(
+                    &client,
+                    request.scope.as_deref(),
+                    auth::id_tokens_supported(),
+                )?;
+                let (access_token, expires_in) =
+                    auth::generate_client_credentials_token(&client.client_id, &scope)?;
+                Ok(OAuthTokenResponse {
+                    access_token,
+                    token_type: "Bearer".to_string(),
+                    expires_in,
+                    refresh_token: None,
+                    scope,
+                    id_token: None,
+                })
+            }
+            _ => Err(OAuthError::UnsupportedGrantType),
+        }
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-store"))
+        .insert_header((PRAGMA, "no-cache"))
+        .json(response))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IntrospectionRequest {
+    pub token: String,
+    pub token_type_hint: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+/// RFC 7662 answers with `active` alone for tokens that are not, so callers learn
+/// nothing about them. Active tokens come with the standard members only; roles
+/// and connection ids stay internal.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct IntrospectionResponse {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+    /// Named like the `token_type_hint` values.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<String>,
+}
+
+impl IntrospectionResponse {
+    /// Client credentials tokens act for no user, so they have no subject.
+    fn active(claims: TokenClaims) -> Self {
+        let sub = claims
+            .user_uuid()
+            .ok()
+            .filter(|user_id| !user_id.is_nil())
+            .map(|user_id| user_id.to_string());
+        let token_type = match claims.token_kind {
+            auth::TokenKind::Refresh => "refresh_token",
+            _ => "access_token",
+        };
+        Self {
+            active: true,
+            scope: claims.scope,
+            client_id: claims.client_id,
+            sub,
+            exp: Some(claims.exp),
+            iat: claims.iat,
+            token_type: Some(token_type.to_string()),
+        }
+    }
+}
+
+/// Checks the signature, audience and expiry of an access or refresh token, trying
+/// the kind the caller hinted at first.
+fn decode_token(presented_token: &str, token_type_hint: Option<&str>) -> Option<TokenClaims> {
+    let kinds = match token_type_hint {
+        Some("refresh_token") => [auth::TokenKind::Refresh, auth::TokenKind::Access],
+        _ => [auth::TokenKind::Access, auth::TokenKind::Refresh],
+    };
+    kinds
+        .into_iter()
+        .find_map(|kind| auth::get_claims_and_validate(presented_token.to_string(), kind).ok())
+}
+
+/// Lets services that receive our tokens ask whether one is still good, which the
+/// signature alone can't tell once its connection has ended. Only confidential
+/// clients may ask, so the endpoint can't be used to scan for tokens.
+#[post("/introspect")]
+pub async fn introspect(
+    pool: web::Data<DbPool>,
+    basic: Option<BasicAuth>,
+    request: web::Form<IntrospectionRequest>,
+) -> Result<HttpResponse, OAuthError> {
+    let request = request.into_inner();
+    let (client_id, client_secret) = match basic {
+        Some(basic) => (
+            Some(basic.user_id().to_string()),
+            basic.password().map(|password| password.to_string()),
+        ),
+        None => (request.client_id.clone(), request.client_secret.clone()),
+    };
+    let claims = decode_token(&request.token, request.token_type_hint.as_deref());
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    let claims = web::block(move || {
+        let client =
+            authenticate_client(&mut conn, client_id.as_deref(), client_secret.as_deref())?;
+        if client.client_secret_hash.is_none() {
+            return Err(OAuthError::InvalidClient);
+        }
+        let Some(claims) = claims else {
+            return Ok(None);
+        };
+
+        let user_id = claims.user_uuid()?;
+        let active = if user_id.is_nil() {
+            // Client credentials tokens have no connection; they last as long as
+            // the client that got them.
+            match &claims.client_id {
+                Some(client_id) => db::get_oauth_client(&mut conn, client_id)?.is_some(),
+                None => false,
+            }
+        } else {
+            match db::validate_connection(&mut conn, user_id, claims.connection_uuid()?) {
+                Ok(()) => true,
+                Err(UserError::ExpiredToken) => false,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        Ok(active.then_some(claims))
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-store"))
+        .json(
+            claims
+                .map(IntrospectionResponse::active)
+                .unwrap_or_default(),
+        ))
+}
+
+/// OpenID Connect discovery, so standard client libraries can configure
+/// themselves from the issuer URL alone. Not found while the signing key is a
+/// shared secret, since no client could verify our ID tokens.
+#[get("/.well-known/openid-configuration")]
+pub async fn openid_configuration() -> HttpResponse {
+    let keyring = keys::keyring();
+    let Some(signing_key) = keyring.public_signing_key() else {
+        return HttpResponse::NotFound().finish();
+    };
+    let issuer = auth::oidc_issuer();
+    HttpResponse::Ok().json(serde_json::json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/oauth/authorize", issuer),
+        "token_endpoint": format!("{}/oauth/token", issuer),
+        "userinfo_endpoint": format!("{}/userinfo", issuer),
+        "jwks_uri": format!("{}/.well-known/jwks.json", issuer),
+        "response_types_supported": ["code"],
+        "grant_types_supported": [AUTHORIZATION_CODE, REFRESH_TOKEN, CLIENT_CREDENTIALS],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": [
+            format!("{:?}", signing_key.algorithm)
+        ],
+        "scopes_supported": ["openid", "email", "profile"],
+        "claims_supported": ["sub", "email", "email_verified", "nickname"],
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+        "code_challenge_methods_supported": ["S256"],
+    }))
+}
+
+/// Behind `AuthValidator::scoped("openid")`, so the connection behind the token
+/// must still be active.
+#[get("")]
+pub async fn userinfo(
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<TokenClaims>,
+) -> Result<HttpResponse, OAuthError> {
+    let user_id = claims.user_uuid()?;
+    let scope = claims.scope.clone().unwrap_or_default();
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    let user = web::block(move || db::get_user(&mut conn, user_id)).await??;
+
+    Ok(HttpResponse::Ok().json(auth::UserInfo::new(&user, &scope)))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::to_bytes;
+
+    use super::*;
+
+    fn client(scopes: &[&str]) -> OAuthClient {
+        OAuthClient {
+            id_oauth_client: Uuid::new_v4(),
+            client_id: "movies-spa".to_string(),
+            client_secret_hash: None,
+            name: "Movies".to_string(),
+            redirect_uris: vec![Some("https://movies.rl.com/callback".to_string())],
+            grant_types: vec![Some(AUTHORIZATION_CODE.to_string())],
+            scopes: scopes.iter().map(|scope| Some(scope.to_string())).collect(),
+            first_party: false,
+            created_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+
+    fn authorization_request() -> AuthorizationRequest {
+        AuthorizationRequest {
+            response_type: "code".to_string(),
+            client_id: "movies-spa".to_string(),
+            redirect_uri: "https://movies.rl.com/callback".to_string(),
+            scope: Some("reviews".to_string()),
+            state: Some("xyz".to_string()),
+            code_challenge: Some("E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM".to_string()),
+            code_challenge_method: Some("S256".to_string()),
+            nonce: None,
+        }
+    }
+
+    #[test]
+    fn verifies_the_rfc_7636_example_challenge() {
+        let challenge = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+
+        assert!(verify_code_challenge(
+            "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk",
+            challenge
+        ));
+        assert!(!verify_code_challenge(
+            "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXj",
+            challenge
+        ));
+    }
+
+    #[test]
+    fn only_grants_scopes_and_redirects_on_the_allow_list() {
+        let client = client(&["reviews", "favorites"]);
+        let request = authorization_request();
+
+        let (_, scope) = validate_authorization_request(Some(client.clone()), &request).unwrap();
+        assert_eq!(scope, "reviews");
+        assert_eq!(
+            granted_scope(&client, None, true).unwrap(),
+            "reviews favorites"
+        );
+        assert!(matches!(
+            granted_scope(&client, Some("reviews admin"), true),
+            Err(OAuthError::InvalidScope)
+        ));
+
+        let other_redirect = AuthorizationRequest {
+            redirect_uri: "https://movies.rl.com/callback/../evil".to_string(),
+            ..request.clone()
+        };
+        assert!(matches!(
+            validate_authorization_request(Some(client.clone()), &other_redirect),
+            Err(OAuthError::InvalidRequest(_))
+        ));
+        let plain_pkce = AuthorizationRequest {
+            code_challenge_method: Some("plain".to_string()),
+            ..request
+        };
+        assert!(matches!(
+            validate_authorization_request(Some(client), &plain_pkce),
+            Err(OAuthError::InvalidRequest(_))
+        ));
+    }
+
+    #[test]
+    fn only_grants_openid_while_id_tokens_can_be_signed() {
+        let client = client(&["openid", "reviews"]);
+
+        assert_eq!(
+            granted_scope(&client, Some("openid reviews"), true).unwrap(),
+            "openid reviews"
+        );
+        assert_eq!(granted_scope(&client, None, false).unwrap(), "reviews");
+        assert!(matches!(
+            granted_scope(&client, Some("openid reviews"), false),
+            Err(OAuthError::InvalidScope)
+        ));
+    }
+
+    #[test]
+    fn matches_client_secrets_against_their_hash() {
+        let secret = generate_token();
+        let secret_hash = hash_token(&secret);
+
+        assert!(client_secret_matches(&secret_hash, &secret));
+        assert!(!client_secret_matches(&secret_hash, &generate_token()));
+        assert!(!client_secret_matches(&secret_hash, ""));
+    }
+
+    #[test]
+    fn only_the_users_own_session_approves_authorizations() {
+        env::set_var("SECRET", "test-secret");
+        env::set_var("ACCESS_TOKEN_EXP_SEC", "180");
+        env::set_var("REFRESH_TOKEN_EXP_DAY", "90");
+        let user_id = Uuid::new_v4();
+        let (session_token, _, _) =
+            auth::generate_tokens(user_id, Uuid::new_v4(), vec!["USER".to_string()]).unwrap();
+        let (client_token, _, _) = auth::generate_oauth_tokens(
+            user_id,
+            Uuid::new_v4(),
+            vec!["USER".to_string()],
+            "movies-spa",
+            "reviews",
+        )
+        .unwrap();
+
+        let session =
+            auth::get_claims_and_validate(session_token, auth::TokenKind::Access).unwrap();
+        let client = auth::get_claims_and_validate(client_token, auth::TokenKind::Access).unwrap();
+
+        assert_eq!(approving_user(&session).unwrap(), user_id);
+        assert!(matches!(approving_user(&client), Err(UserError::Forbidden)));
+    }
+
+    #[test]
+    fn decodes_either_token_kind_and_only_shows_claims_when_active() {
+        env::set_var("SECRET", "test-secret");
+        env::set_var("ACCESS_TOKEN_EXP_SEC", "180");
+        env::set_var("REFRESH_TOKEN_EXP_DAY", "90");
+        let (access_token, refresh_token, _) =
+            auth::generate_tokens(Uuid::new_v4(), Uuid::new_v4(), vec!["USER".to_string()])
+                .unwrap();
+
+        let claims = decode_token(&refresh_token, None).unwrap();
+        assert_eq!(claims.token_kind, auth::TokenKind::Refresh);
+        let claims = decode_token(&access_token, Some("refresh_token")).unwrap();
+        assert_eq!(claims.token_kind, auth::TokenKind::Access);
+        assert!(decode_token("not-a-token", None).is_none());
+
+        assert_eq!(
+            serde_json::to_value(IntrospectionResponse::default()).unwrap(),
+            serde_json::json!({ "active": false })
+        );
+        let active = serde_json::to_value(IntrospectionResponse::active(claims.clone())).unwrap();
+        assert_eq!(
+            active,
+            serde_json::json!({
+                "active": true,
+                "sub": claims.user_id,
+                "exp": claims.exp,
+                "iat": claims.iat,
+                "token_type": "access_token",
+            })
+        );
+
+        let (client_token, _) =
+            auth::generate_client_credentials_token("reporting", "users:read").unwrap();
+        let claims = decode_token(&client_token, None).unwrap();
+        let active = serde_json::to_value(IntrospectionResponse::active(claims)).unwrap();
+        assert_eq!(active["client_id"], "reporting");
+        assert_eq!(active["scope"], "users:read");
+        assert!(active.get("sub").is_none());
+    }
+
+    #[actix_web::test]
+    async fn reports_token_errors_in_the_oauth_format() {
+        let response = OAuthError::from(UserError::RefreshTokenReused).error_response();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers().get(CACHE_CONTROL).unwrap(), "no-store");
+        let body: OAuthErrorResponse =
+            serde_json::from_slice(&to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_eq!(body.error, "invalid_grant");
+
+        let response =
+            OAuthError::from(UserError::AccountLocked { retry_after: 5 }).error_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
@@ -0,0 +1,70 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+const CODE_DIGITS: u32 = 6;
+
+/// 256 bits from the OS RNG, URL-safe so it can go straight into a link.
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// A six digit code for the user to type in, drawn without modulo bias.
+pub fn generate_code() -> String {
+    let modulus = 10u32.pow(CODE_DIGITS);
+    let limit = u32::MAX - u32::MAX % modulus;
+    loop {
+        let value = OsRng.next_u32();
+        if value < limit {
+            return format!("{:0width$}", value % modulus, width = CODE_DIGITS as usize);
+        }
+    }
+}
+
+/// Short codes would be trivial to look up from an unsalted hash, so each one is
+/// hashed together with the id of the row it is stored in.
+pub fn hash_code(id: Uuid, code: &str) -> String {
+    hash_token(&format!("{}:{}", id, code.trim()))
+}
+
+/// Only this hash is stored, so a leaked table can't be replayed. The tokens are
+/// random enough that a fast unsalted hash is sufficient.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_distinct_tokens_with_stable_hashes() {
+        let token = generate_token();
+
+        assert_eq!(token.len(), 43);
+        assert_ne!(token, generate_token());
+        assert_eq!(hash_token(&token), hash_token(&token));
+        assert_eq!(
+            hash_token("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn generates_six_digit_codes_hashed_per_row() {
+        let code = generate_code();
+        let (id, other_id) = (Uuid::new_v4(), Uuid::new_v4());
+
+        assert_eq!(code.len(), 6);
+        assert!(code.chars().all(|c| c.is_ascii_digit()));
+        assert_eq!(hash_code(id, &code), hash_code(id, &format!(" {} ", code)));
+        assert_ne!(hash_code(id, &code), hash_code(other_id, &code));
+    }
+}
@@ -0,0 +1,376 @@
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use anyhow::anyhow;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use bcrypt::DEFAULT_COST;
+
+use crate::users::{FieldError, UserError};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PasswordHashing {
+    Argon2id {
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+    Bcrypt {
+        cost: u32,
+    },
+}
+
+impl PasswordHashing {
+    pub fn from_env() -> Self {
+        let algorithm =
+            env::var("PASSWORD_HASH_ALGORITHM").unwrap_or_else(|_| "argon2id".to_string());
+        match algorithm.to_lowercase().as_str() {
+            "bcrypt" => PasswordHashing::Bcrypt {
+                cost: env_number("BCRYPT_COST", DEFAULT_COST),
+            },
+            "argon2id" => PasswordHashing::Argon2id {
+                memory_kib: env_number("ARGON2_MEMORY_KIB", Params::DEFAULT_M_COST),
+                iterations: env_number("ARGON2_ITERATIONS", Params::DEFAULT_T_COST),
+                parallelism: env_number("ARGON2_PARALLELISM", Params::DEFAULT_P_COST),
+            },
+            _ => panic!("PASSWORD_HASH_ALGORITHM must be argon2id or bcrypt."),
+        }
+    }
+
+    pub fn hash(&self, password: &str) -> anyhow::Result<String> {
+        match self {
+            PasswordHashing::Argon2id { .. } => {
+                let salt = SaltString::generate(&mut OsRng);
+                let hash = self
+                    .argon2()?
+                    .hash_password(password.as_bytes(), &salt)
+                    .map_err(|e| anyhow!("Failed to hash password: {}", e))?;
+                Ok(hash.to_string())
+            }
+            PasswordHashing::Bcrypt { cost } => {
+                bcrypt::hash(password, *cost).map_err(|e| anyhow!("Failed to hash password: {}", e))
+            }
+        }
+    }
+
+    /// Whether a hash that just verified should be replaced by one made with the
+    /// current algorithm and parameters.
+    pub fn needs_rehash(&self, stored_hash: &str) -> bool {
+        match self {
+            PasswordHashing::Argon2id { .. } => {
+                let Ok(hash) = PasswordHash::new(stored_hash) else {
+                    return true;
+                };
+                let Ok(params) = self.params() else {
+                    return false;
+                };
+                hash.algorithm != argon2::Algorithm::Argon2id.ident()
+                    || Params::try_from(&hash).map_or(true, |current| {
+                        current.m_cost() != params.m_cost()
+                            || current.t_cost() != params.t_cost()
+                            || current.p_cost() != params.p_cost()
+                    })
+            }
+            PasswordHashing::Bcrypt { cost } => bcrypt::HashParts::from_str(stored_hash)
+                .map_or(true, |parts| parts.get_cost() != *cost),
+        }
+    }
+
+    fn params(&self) -> anyhow::Result<Params> {
+        match self {
+            PasswordHashing::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => Params::new(*memory_kib, *iterations, *parallelism, None)
+                .map_err(|e| anyhow!("Invalid Argon2 parameters: {}", e)),
+            PasswordHashing::Bcrypt { .. } => Err(anyhow!("bcrypt has no Argon2 parameters")),
+        }
+    }
+
+    fn argon2(&self) -> anyhow::Result<Argon2<'static>> {
+        Ok(Argon2::new(
+            argon2::Algorithm::Argon2id,
+            Version::V0x13,
+            self.params()?,
+        ))
+    }
+}
+
+pub fn hashing() -> &'static PasswordHashing {
+    static HASHING: OnceLock<PasswordHashing> = OnceLock::new();
+    HASHING.get_or_init(PasswordHashing::from_env)
+}
+
+/// Verifies against either an Argon2 PHC string or a legacy bcrypt hash.
+pub fn verify_password(password: &str, stored_hash: &str) -> bool {
+    if stored_hash.starts_with("$argon2") {
+        PasswordHash::new(stored_hash)
+            .map(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            })
+            .unwrap_or(false)
+    } else {
+        bcrypt::verify(password, stored_hash).unwrap_or(false)
+    }
+}
+
+/// Unknown emails still pay for one hash with the current parameters, so they fail
+/// with the same error and roughly the same latency as a wrong password.
+pub fn check_password(
+    hashing: &PasswordHashing,
+    password: &str,
+    stored_hash: Option<&str>,
+) -> Result<(), UserError> {
+    let Some(stored_hash) = stored_hash else {
+        hashing.hash(password)?;
+        return Err(UserError::InvalidCredentials);
+    };
+    if !verify_password(password, stored_hash) {
+        return Err(UserError::InvalidCredentials);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    /// bcrypt silently ignores everything past 72 bytes.
+    pub max_bytes: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    pub breached_passwords: HashSet<String>,
+}
+
+impl PasswordPolicy {
+    pub fn from_env() -> Self {
+        let breached_passwords = env::var("BREACHED_PASSWORDS_PATH")
+            .map(|path| {
+                fs::read_to_string(&path)
+                    .unwrap_or_else(|_| panic!("Couldn't read breached passwords from {}.", path))
+                    .lines()
+                    .map(|line| line.trim().to_lowercase())
+                    .filter(|line| !line.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            min_length: env_number("PASSWORD_MIN_LENGTH", 8) as usize,
+            max_bytes: env_number("PASSWORD_MAX_BYTES", 72) as usize,
+            require_lowercase: env_flag("PASSWORD_REQUIRE_LOWERCASE"),
+            require_uppercase: env_flag("PASSWORD_REQUIRE_UPPERCASE"),
+            require_digit: env_flag("PASSWORD_REQUIRE_DIGIT"),
+            require_symbol: env_flag("PASSWORD_REQUIRE_SYMBOL"),
+            breached_passwords,
+        }
+    }
+
+    /// Returns one message per broken rule, so clients can show them all at once.
+    pub fn violations(&self, password: &str, email: &str, nickname: &str) -> Vec<String> {
+        let mut violations = Vec::new();
+        if password.chars().count() < self.min_length {
+            violations.push(format!(
+                "Must be at least {} characters long.",
+                self.min_length
+            ));
+        }
+        if password.len() > self.max_bytes {
+            violations.push(format!("Must be at most {} bytes long.", self.max_bytes));
+        }
+        if self.require_lowercase && !password.chars().any(char::is_lowercase) {
+            violations.push("Must contain a lowercase letter.".to_string());
+        }
+        if self.require_uppercase && !password.chars().any(char::is_uppercase) {
+            violations.push("Must contain an uppercase letter.".to_string());
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            violations.push("Must contain a digit.".to_string());
+        }
+        if self.require_symbol && password.chars().all(char::is_alphanumeric) {
+            violations.push("Must contain a symbol.".to_string());
+        }
+
+        let lowercase = password.to_lowercase();
+        let local_part = email.split('@').next().unwrap_or_default();
+        if contains_identifier(&lowercase, local_part) {
+            violations.push("Must not contain your email.".to_string());
+        }
+        if contains_identifier(&lowercase, nickname) {
+            violations.push("Must not contain your nickname.".to_string());
+        }
+        if self.breached_passwords.contains(&lowercase) {
+            violations.push("Has appeared in a data breach. Choose another one.".to_string());
+        }
+        violations
+    }
+
+    pub fn check(
+        &self,
+        field: &str,
+        password: &str,
+        email: &str,
+        nickname: &str,
+    ) -> Result<(), UserError> {
+        let violations = self.violations(password, email, nickname);
+        if violations.is_empty() {
+            return Ok(());
+        }
+        Err(UserError::WeakPassword(
+            violations
+                .into_iter()
+                .map(|message| FieldError::new(field, message))
+                .collect(),
+        ))
+    }
+}
+
+pub fn policy() -> &'static PasswordPolicy {
+    static POLICY: OnceLock<PasswordPolicy> = OnceLock::new();
+    POLICY.get_or_init(PasswordPolicy::from_env)
+}
+
+/// Identifiers shorter than three characters would reject far too many passwords.
+fn contains_identifier(password: &str, identifier: &str) -> bool {
+    let identifier = identifier.trim().to_lowercase();
+    identifier.chars().count() >= 3 && password.contains(&identifier)
+}
+
+fn env_flag(name: &str) -> bool {
+    env::var(name)
+        .map(|value| {
+            value
+                .parse()
+                .unwrap_or_else(|_| panic!("{} must be true or false.", name))
+        })
+        .unwrap_or(false)
+}
+
+fn env_number(name: &str, default: u32) -> u32 {
+    env::var(name)
+        .map(|value| {
+            value
+                .parse()
+                .unwrap_or_else(|_| panic!("{} must be a number.", name))
+        })
+        .unwrap_or(default)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::ResponseError;
+
+    use super::*;
+
+    fn cheap_argon2() -> PasswordHashing {
+        PasswordHashing::Argon2id {
+            memory_kib: 1024,
+            iterations: 1,
+            parallelism: 1,
+        }
+    }
+
+    #[test]
+    fn accepts_the_right_password() {
+        let hashing = cheap_argon2();
+        let stored_hash = hashing.hash("correct horse").unwrap();
+
+        assert!(check_password(&hashing, "correct horse", Some(&stored_hash)).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_wrong_password() {
+        let hashing = cheap_argon2();
+        let stored_hash = hashing.hash("correct horse").unwrap();
+
+        let result = check_password(&hashing, "battery staple", Some(&stored_hash));
+
+        assert!(matches!(result, Err(UserError::InvalidCredentials)));
+    }
+
+    #[test]
+    fn rejects_an_unknown_email_like_a_wrong_password() {
+        let result = check_password(&cheap_argon2(), "correct horse", None);
+
+        assert!(matches!(result, Err(UserError::InvalidCredentials)));
+    }
+
+    #[test]
+    fn verifies_legacy_bcrypt_hashes_and_flags_them_for_rehash() {
+        let hashing = cheap_argon2();
+        let stored_hash = bcrypt::hash("correct horse", 4).unwrap();
+
+        assert!(check_password(&hashing, "correct horse", Some(&stored_hash)).is_ok());
+        assert!(hashing.needs_rehash(&stored_hash));
+    }
+
+    #[test]
+    fn flags_argon2_hashes_with_outdated_parameters() {
+        let hashing = cheap_argon2();
+        let stored_hash = hashing.hash("correct horse").unwrap();
+        let stronger = PasswordHashing::Argon2id {
+            memory_kib: 2048,
+            iterations: 1,
+            parallelism: 1,
+        };
+
+        assert!(!hashing.needs_rehash(&stored_hash));
+        assert!(stronger.needs_rehash(&stored_hash));
+    }
+
+    fn strict_policy() -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: 10,
+            max_bytes: 72,
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_symbol: true,
+            breached_passwords: HashSet::from(["correct-horse-1a".to_string()]),
+        }
+    }
+
+    #[test]
+    fn accepts_a_password_that_follows_the_policy() {
+        let policy = strict_policy();
+
+        assert!(policy
+            .check("password", "Battery-Staple-9", "john@rl.com", "Johnny")
+            .is_ok());
+    }
+
+    #[test]
+    fn reports_every_broken_rule_against_the_password_field() {
+        let policy = strict_policy();
+
+        let error = policy
+            .check("password", "john", "john@rl.com", "Joe")
+            .unwrap_err();
+
+        assert_eq!(error.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        let UserError::WeakPassword(errors) = error else {
+            panic!("expected a weak password error");
+        };
+
+        assert!(errors.iter().all(|error| error.field == "password"));
+        assert_eq!(errors.len(), 5);
+        assert!(policy
+            .violations(&"Aa1-".repeat(19), "john@rl.com", "Joe")
+            .contains(&"Must be at most 72 bytes long.".to_string()));
+        assert!(policy
+            .violations("Xx-johnny-2024", "john@rl.com", "Johnny")
+            .contains(&"Must not contain your nickname.".to_string()));
+        assert!(policy
+            .violations("Correct-Horse-1A", "john@rl.com", "Joe")
+            .contains(&"Has appeared in a data breach. Choose another one.".to_string()));
+    }
+}
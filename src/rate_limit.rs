@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+use std::env;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::web::Bytes;
+use actix_web::{Error, ResponseError};
+use futures_util::future::LocalBoxFuture;
+
+use crate::client_ip;
+use crate::users::{normalize_email, UserError};
+
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitRule {
+    pub capacity: u32,
+    pub period: Duration,
+}
+
+impl RateLimitRule {
+    /// Reads a `<capacity>/<seconds>` rule, e.g. `10/60` for ten requests a minute.
+    pub fn from_env(name: &str) -> Self {
+        let value = env::var(name).unwrap_or_else(|_| panic!("{} must be set.", name));
+        let (capacity, seconds) = value
+            .split_once('/')
+            .and_then(|(capacity, seconds)| {
+                Some((capacity.trim().parse().ok()?, seconds.trim().parse().ok()?))
+            })
+            .filter(|(capacity, _)| *capacity > 0)
+            .unwrap_or_else(|| {
+                panic!(
+                    "{} must look like <capacity>/<seconds> with a capacity above 0.",
+                    name
+                )
+            });
+        Self {
+            capacity,
+            period: Duration::from_secs(seconds),
+        }
+    }
+
+    fn refill_per_second(&self) -> f64 {
+        self.capacity as f64 / self.period.as_secs_f64().max(1.0)
+    }
+}
+
+pub trait RateLimitStore: Send + Sync {
+    /// Takes a token from the bucket behind `key`, or returns how long to wait
+    /// until one is available.
+    fn take(&self, key: &str, rule: &RateLimitRule) -> Result<(), Duration>;
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    /// Routes have different periods, so each bucket is pruned by its own.
+    period: Duration,
+}
+
+#[derive(Default)]
+pub struct InMemoryStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl InMemoryStore {
+    fn take_at(&self, key: &str, rule: &RateLimitRule, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self
+            .buckets
+            .lock()
+            .expect("Rate limit store lock poisoned.");
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| now.duration_since(bucket.updated_at) < bucket.period);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: rule.capacity as f64,
+            updated_at: now,
+            period: rule.period,
+        });
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens =
+            (bucket.tokens + elapsed * rule.refill_per_second()).min(rule.capacity as f64);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let missing = 1.0 - bucket.tokens;
+        Err(Duration::from_secs_f64(missing / rule.refill_per_second()))
+    }
+}
+
+impl RateLimitStore for InMemoryStore {
+    fn take(&self, key: &str, rule: &RateLimitRule) -> Result<(), Duration> {
+        self.take_at(key, rule, Instant::now())
+    }
+}
+
+#[derive(Clone)]
+struct RouteLimit {
+    path: String,
+    rule: RateLimitRule,
+    by_email: bool,
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    store: Arc<dyn RateLimitStore>,
+    routes: Arc<Vec<RouteLimit>>,
+}
+
+impl RateLimiter {
+    pub fn new(store: Arc<dyn RateLimitStore>) -> Self {
+        Self {
+            store,
+            routes: Arc::new(Vec::new()),
+        }
+    }
+
+    /// Limits `path` per client IP, as resolved by `client_ip`.
+    pub fn limit(self, path: &str, rule: RateLimitRule) -> Self {
+        self.add_route(path, rule, false)
+    }
+
+    /// Limits `path` per client IP and, separately, per `email` in the JSON body.
+    pub fn limit_by_email(self, path: &str, rule: RateLimitRule) -> Self {
+        self.add_route(path, rule, true)
+    }
+
+    fn add_route(self, path: &str, rule: RateLimitRule, by_email: bool) -> Self {
+        let mut routes = self.routes.as_ref().clone();
+        routes.push(RouteLimit {
+            path: path.to_string(),
+            rule,
+            by_email,
+        });
+        Self {
+            store: self.store,
+            routes: Arc::new(routes),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimiterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimiterMiddleware {
+            service: Rc::new(service),
+            store: self.store.clone(),
+            routes: self.routes.clone(),
+        }))
+    }
+}
+
+pub struct RateLimiterMiddleware<S> {
+    service: Rc<S>,
+    store: Arc<dyn RateLimitStore>,
+    routes: Arc<Vec<RouteLimit>>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimiterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let store = self.store.clone();
+        let route = self
+            .routes
+            .iter()
+            .find(|route| route.path == req.path())
+            .cloned();
+
+        Box::pin(async move {
+            let Some(route) = route else {
+                return service.call(req).await.map(|res| res.map_into_left_body());
+            };
+
+            let ip_address =
+                client_ip::client_ip(req.request()).unwrap_or_else(|| "unknown".to_string());
+            let mut keys = vec![format!("ip:{}:{}", route.path, ip_address)];
+            if route.by_email {
+                let body = req.extract::<Bytes>().await?;
+                if let Some(email) = email_from_body(&body) {
+                    keys.push(format!("email:{}:{}", route.path, email));
+                }
+                req.set_payload(Payload::from(body));
+            }
+
+            for key in keys {
+                if let Err(wait) = store.take(&key, &route.rule) {
+                    let error = UserError::TooManyRequests {
+                        retry_after: (wait.as_secs_f64().ceil() as i64).max(1),
+                    };
+                    return Ok(req
+                        .into_response(error.error_response())
+                        .map_into_right_body());
+                }
+            }
+
+            service.call(req).await.map(|res| res.map_into_left_body())
+        })
+    }
+}
+
+fn email_from_body(body: &Bytes) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_slice(body).ok()?;
+    let email = normalize_email(value.get("email")?.as_str()?);
+    (!email.is_empty()).then_some(email)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::RETRY_AFTER;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    use super::*;
+
+    fn rule() -> RateLimitRule {
+        RateLimitRule {
+            capacity: 2,
+            period: Duration::from_secs(10),
+        }
+    }
+
+    #[test]
+    fn empties_and_refills_the_bucket() {
+        let store = InMemoryStore::default();
+        let start = Instant::now();
+
+        assert!(store.take_at("key", &rule(), start).is_ok());
+        assert!(store.take_at("key", &rule(), start).is_ok());
+        let wait = store.take_at("key", &rule(), start).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(5));
+
+        assert!(store.take_at("key", &rule(), start + wait).is_ok());
+        assert!(store.take_at("other", &rule(), start).is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "with a capacity above 0")]
+    fn refuses_a_rule_that_allows_nothing() {
+        env::set_var("RATE_LIMIT_TEST_EMPTY", "0/60");
+
+        RateLimitRule::from_env("RATE_LIMIT_TEST_EMPTY");
+    }
+
+    #[test]
+    fn prunes_each_bucket_by_its_own_period() {
+        let store = InMemoryStore::default();
+        let start = Instant::now();
+        let hourly = RateLimitRule {
+            capacity: 1,
+            period: Duration::from_secs(3600),
+        };
+        let minutely = RateLimitRule {
+            capacity: 10,
+            period: Duration::from_secs(60),
+        };
+
+        assert!(store.take_at("register", &hourly, start).is_ok());
+        let later = start + Duration::from_secs(120);
+        for i in 0..=PRUNE_THRESHOLD {
+            assert!(store
+                .take_at(&format!("login:{}", i), &minutely, later)
+                .is_ok());
+        }
+        assert!(store.take_at("login:last", &minutely, later).is_ok());
+
+        assert!(store.take_at("register", &hourly, later).is_err());
+    }
+
+    #[actix_web::test]
+    async fn rejects_requests_over_the_limit_per_email() {
+        let limiter =
+            RateLimiter::new(Arc::new(InMemoryStore::default())).limit_by_email("/login", rule());
+        let app = init_service(App::new().wrap(limiter).route(
+            "/login",
+            web::post().to(|body: Bytes| async move { HttpResponse::Ok().body(body) }),
+        ))
+        .await;
+        let login = |email: &str, ip: &str| {
+            TestRequest::post()
+                .uri("/login")
+                .peer_addr(format!("{}:4000", ip).parse().unwrap())
+                .set_json(serde_json::json!({ "email": email }))
+                .to_request()
+        };
+
+        let res = call_service(&app, login("a@rl.com", "10.0.0.1")).await;
+        assert!(res.status().is_success());
+        assert_eq!(read_body(res).await, r#"{"email":"a@rl.com"}"#);
+        let res = call_service(&app, login("A@rl.com", "10.0.0.2")).await;
+        assert!(res.status().is_success());
+        let res = call_service(&app, login("a@rl.com ", "10.0.0.3")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get(RETRY_AFTER).unwrap(), "5");
+        let res = call_service(&app, login("b@rl.com", "10.0.0.3")).await;
+        assert!(res.status().is_success());
+        let res = call_service(&app, login("jose\u{301}@rl.com", "10.0.0.4")).await;
+        assert!(res.status().is_success());
+        let res = call_service(&app, login("jos\u{e9}@rl.com", "10.0.0.5")).await;
+        assert!(res.status().is_success());
+        let res = call_service(&app, login("JOSE\u{301}@rl.com", "10.0.0.6")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let res = call_service(&app, login("c@rl.com", "10.0.0.6")).await;
+        assert!(res.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn forged_forwarded_for_does_not_reset_the_ip_bucket() {
+        let limiter = RateLimiter::new(Arc::new(InMemoryStore::default())).limit("/mfa", rule());
+        let app = init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/mfa", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+        let attempt = |forwarded_for: &str| {
+            TestRequest::post()
+                .uri("/mfa")
+                .peer_addr("203.0.113.7:4000".parse().unwrap())
+                .insert_header(("X-Forwarded-For", forwarded_for))
+                .insert_header(("Forwarded", format!("for={}", forwarded_for)))
+                .to_request()
+        };
+
+        assert!(call_service(&app, attempt("10.0.0.1"))
+            .await
+            .status()
+            .is_success());
+        assert!(call_service(&app, attempt("10.0.0.2"))
+            .await
+            .status()
+            .is_success());
+        let res = call_service(&app, attempt("10.0.0.3")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
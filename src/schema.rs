@@ -31,6 +31,13 @@ diesel::table! {
         id_user -> Uuid,
         connect_at -> Nullable<Timestamp>,
         ended_at -> Nullable<Timestamp>,
+        id_session -> Uuid,
+        user_agent -> Nullable<Text>,
+        ip_address -> Nullable<Text>,
+        #[max_length = 100]
+        device_label -> Nullable<Varchar>,
+        refreshed_at -> Nullable<Timestamp>,
+        rotated_at -> Nullable<Timestamp>,
     }
 }
 
@@ -43,6 +50,60 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    email_changes (id_email_change) {
+        id_email_change -> Uuid,
+        id_user -> Uuid,
+        #[max_length = 200]
+        old_email -> Varchar,
+        #[max_length = 200]
+        new_email -> Varchar,
+        #[max_length = 64]
+        token_hash -> Varchar,
+        #[max_length = 64]
+        cancel_token_hash -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        confirmed_at -> Nullable<Timestamp>,
+        cancelled_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    email_verifications (id_email_verification) {
+        id_email_verification -> Uuid,
+        id_user -> Uuid,
+        #[max_length = 200]
+        email -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    login_attempts (attempt_key) {
+        #[max_length = 300]
+        attempt_key -> Varchar,
+        failed_count -> Int4,
+        last_failed_at -> Nullable<Timestamp>,
+        locked_until -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    login_codes (id_login_code) {
+        id_login_code -> Uuid,
+        id_user -> Uuid,
+        #[max_length = 64]
+        code_hash -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        failed_attempts -> Int4,
+    }
+}
+
 diesel::table! {
     movies (id_movie) {
         id_movie -> Int8,
@@ -55,6 +116,64 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    oauth_authorization_codes (id_oauth_authorization_code) {
+        id_oauth_authorization_code -> Uuid,
+        #[max_length = 64]
+        code_hash -> Varchar,
+        id_oauth_client -> Uuid,
+        id_user -> Uuid,
+        redirect_uri -> Text,
+        scope -> Text,
+        #[max_length = 128]
+        code_challenge -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        #[max_length = 255]
+        nonce -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    oauth_clients (id_oauth_client) {
+        id_oauth_client -> Uuid,
+        #[max_length = 100]
+        client_id -> Varchar,
+        #[max_length = 64]
+        client_secret_hash -> Nullable<Varchar>,
+        #[max_length = 100]
+        name -> Varchar,
+        redirect_uris -> Array<Nullable<Text>>,
+        grant_types -> Array<Nullable<Text>>,
+        scopes -> Array<Nullable<Text>>,
+        first_party -> Bool,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    password_resets (id_password_reset) {
+        id_password_reset -> Uuid,
+        id_user -> Uuid,
+        #[max_length = 64]
+        token_hash -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    recovery_codes (id_recovery_code) {
+        id_recovery_code -> Uuid,
+        id_user -> Uuid,
+        #[max_length = 64]
+        code_hash -> Varchar,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
 diesel::table! {
     reviews (id_review) {
         id_review -> Int8,
@@ -82,6 +201,20 @@ diesel::table! {
         nickname -> Varchar,
         password -> Text,
         id_role -> Int4,
+        email_verified_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    security_events (id_security_event) {
+        id_security_event -> Uuid,
+        id_user -> Uuid,
+        #[max_length = 50]
+        event_type -> Varchar,
+        id_session -> Nullable<Uuid>,
+        ip_address -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+        created_at -> Nullable<Timestamp>,
     }
 }
 
@@ -93,15 +226,65 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    user_totp (id_user) {
+        id_user -> Uuid,
+        #[max_length = 64]
+        secret -> Varchar,
+        created_at -> Timestamp,
+        confirmed_at -> Nullable<Timestamp>,
+        last_used_step -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    webauthn_challenges (id_webauthn_challenge) {
+        id_webauthn_challenge -> Uuid,
+        id_user -> Nullable<Uuid>,
+        #[max_length = 64]
+        challenge -> Varchar,
+        #[max_length = 20]
+        ceremony -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    webauthn_credentials (id_webauthn_credential) {
+        id_webauthn_credential -> Uuid,
+        id_user -> Uuid,
+        #[max_length = 1400]
+        credential_id -> Varchar,
+        public_key -> Bytea,
+        sign_count -> Int8,
+        #[max_length = 100]
+        label -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
+    }
+}
+
 diesel::joinable!(actors -> movies (id_movie));
 diesel::joinable!(category_movies -> categories (id_category));
 diesel::joinable!(category_movies -> movies (id_movie));
 diesel::joinable!(directors -> movies (id_movie));
+diesel::joinable!(email_changes -> rl_users (id_user));
+diesel::joinable!(email_verifications -> rl_users (id_user));
+diesel::joinable!(login_codes -> rl_users (id_user));
+diesel::joinable!(oauth_authorization_codes -> oauth_clients (id_oauth_client));
+diesel::joinable!(oauth_authorization_codes -> rl_users (id_user));
+diesel::joinable!(password_resets -> rl_users (id_user));
+diesel::joinable!(recovery_codes -> rl_users (id_user));
 diesel::joinable!(reviews -> movies (id_movie));
 diesel::joinable!(reviews -> rl_users (id_user));
 diesel::joinable!(rl_users -> rl_role (id_role));
+diesel::joinable!(security_events -> rl_users (id_user));
 diesel::joinable!(user_favorites -> movies (id_movie));
 diesel::joinable!(user_favorites -> rl_users (id_user));
+diesel::joinable!(user_totp -> rl_users (id_user));
+diesel::joinable!(webauthn_challenges -> rl_users (id_user));
+diesel::joinable!(webauthn_credentials -> rl_users (id_user));
 
 diesel::allow_tables_to_appear_in_same_query!(
     actors,
@@ -109,9 +292,21 @@ diesel::allow_tables_to_appear_in_same_query!(
     category_movies,
     connections,
     directors,
+    email_changes,
+    email_verifications,
+    login_attempts,
+    login_codes,
     movies,
+    oauth_authorization_codes,
+    oauth_clients,
+    password_resets,
+    recovery_codes,
     reviews,
     rl_role,
     rl_users,
+    security_events,
     user_favorites,
+    user_totp,
+    webauthn_challenges,
+    webauthn_credentials,
 );
@@ -2,14 +2,21 @@ use std::str::FromStr;
 
 use actix_web::body::BoxBody;
 use actix_web::error::BlockingError;
+use actix_web::http::header::RETRY_AFTER;
 use actix_web::http::StatusCode;
-use actix_web::{post, web, HttpResponse, ResponseError};
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse, ResponseError};
 use anyhow::anyhow;
-use chrono::NaiveDateTime;
+use chrono::{DateTime, NaiveDateTime};
 use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
 use uuid::Uuid;
+use validator::{Validate, ValidationErrors};
 
-use crate::{auth, db, DbPool};
+use crate::auth::TokenClaims;
+use crate::lockout::AttemptKey;
+use crate::mailer::{app_link, Email, Mailer};
+use crate::model::{RLUser, WebauthnCredential};
+use crate::{auth, client_ip, db, lockout, mfa, one_time_token, password, webauthn, DbPool};
 
 pub type Result<T> = std::result::Result<T, UserError>;
 #[derive(thiserror::Error, Debug)]
@@ -27,7 +34,39 @@ pub enum UserError {
     #[error("Invalid Credentials")]
     InvalidCredentials,
     #[error("Forbidden")]
-    Forbidden
+    Forbidden,
+    #[error("Wrong token type.")]
+    WrongTokenKind,
+    #[error("Session not found")]
+    SessionNotFound,
+    #[error("Refresh token reuse detected. The session has been revoked.")]
+    RefreshTokenReused,
+    #[error("Too many failed login attempts. Retry after {retry_after} seconds.")]
+    AccountLocked { retry_after: i64 },
+    #[error("Too many requests. Retry after {retry_after} seconds.")]
+    TooManyRequests { retry_after: i64 },
+    #[error("The password does not meet the password policy.")]
+    WeakPassword(Vec<FieldError>),
+    #[error("The request is invalid.")]
+    Validation(Vec<FieldError>),
+    #[error("The token is invalid or has expired.")]
+    InvalidToken,
+    #[error("The email address has not been verified.")]
+    EmailNotVerified,
+    #[error("Two-factor authentication is already enabled.")]
+    MfaAlreadyEnabled,
+    #[error("Two-factor authentication has not been set up.")]
+    MfaNotEnrolled,
+    #[error("The passkey could not be verified.")]
+    InvalidPasskey,
+    #[error("Passkey not found")]
+    PasskeyNotFound,
+}
+
+impl From<diesel::result::Error> for UserError {
+    fn from(value: diesel::result::Error) -> Self {
+        UserError::InternalError(anyhow!("{}", value))
+    }
 }
 
 impl UserError {
@@ -39,7 +78,52 @@ impl UserError {
             UserError::ExpiredToken => "ET-00403".to_string(),
             UserError::DatabaseError(_) => "DE-00500".to_string(),
             UserError::InvalidCredentials => "IC-00400".to_string(),
-            UserError::Forbidden => "FB-00401".to_string()
+            UserError::Forbidden => "FB-00401".to_string(),
+            UserError::WrongTokenKind => "WTK-00403".to_string(),
+            UserError::SessionNotFound => "SNF-00404".to_string(),
+            UserError::RefreshTokenReused => "RTR-00403".to_string(),
+            UserError::AccountLocked { .. } => "AL-00429".to_string(),
+            UserError::TooManyRequests { .. } => "TMR-00429".to_string(),
+            UserError::WeakPassword(_) => "WP-00422".to_string(),
+            UserError::Validation(_) => "VE-00422".to_string(),
+            UserError::InvalidToken => "IT-00400".to_string(),
+            UserError::EmailNotVerified => "ENV-00403".to_string(),
+            UserError::MfaAlreadyEnabled => "MAE-00409".to_string(),
+            UserError::MfaNotEnrolled => "MNE-00400".to_string(),
+            UserError::InvalidPasskey => "PK-00400".to_string(),
+            UserError::PasskeyNotFound => "PNF-00404".to_string(),
+        }
+    }
+
+    fn retry_after(&self) -> Option<i64> {
+        match self {
+            UserError::AccountLocked { retry_after } => Some(*retry_after),
+            UserError::TooManyRequests { retry_after } => Some(*retry_after),
+            _ => None,
+        }
+    }
+
+    fn field_errors(&self) -> Option<Vec<FieldError>> {
+        match self {
+            UserError::WeakPassword(errors) => Some(errors.clone()),
+            UserError::Validation(errors) => Some(errors.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: impl Into<String>) -> Self {
+        Self {
+            field: field.to_string(),
+            message: message.into(),
         }
     }
 }
@@ -51,6 +135,8 @@ pub struct UserErrorResponse {
     pub status: u16,
     pub timestamp: NaiveDateTime,
     pub internal_code: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub errors: Option<Vec<FieldError>>,
 }
 
 impl From<&UserError> for UserErrorResponse {
@@ -58,12 +144,50 @@ impl From<&UserError> for UserErrorResponse {
         Self {
             message: value.to_string(),
             status: value.status_code().as_u16(),
-            timestamp: NaiveDateTime::from_timestamp_opt(chrono::Utc::now().timestamp(), 0)
+            timestamp: DateTime::from_timestamp(chrono::Utc::now().timestamp(), 0)
+                .map(|t| t.naive_utc())
                 .unwrap_or_default(),
             internal_code: value.get_error_code(),
+            errors: value.field_errors(),
         }
     }
 }
+impl From<ValidationErrors> for UserError {
+    fn from(value: ValidationErrors) -> Self {
+        let mut errors = value
+            .field_errors()
+            .into_iter()
+            .flat_map(|(field, errors)| {
+                errors.iter().map(move |error| {
+                    FieldError::new(
+                        &camel_case(field),
+                        error
+                            .message
+                            .as_ref()
+                            .map_or_else(|| error.code.to_string(), |m| m.to_string()),
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        errors.sort_by(|a, b| a.field.cmp(&b.field));
+        UserError::Validation(errors)
+    }
+}
+
+/// Field names as the client sent them, e.g. `new_email` -> `newEmail`.
+fn camel_case(field: &str) -> String {
+    let mut parts = field.split('_');
+    let first = parts.next().unwrap_or_default().to_string();
+    parts.fold(first, |mut name, part| {
+        let mut chars = part.chars();
+        if let Some(c) = chars.next() {
+            name.extend(c.to_uppercase());
+            name.push_str(chars.as_str());
+        }
+        name
+    })
+}
+
 impl ResponseError for UserError {
     fn status_code(&self) -> StatusCode {
         match &self {
@@ -71,22 +195,95 @@ impl ResponseError for UserError {
             UserError::UserNotFound => StatusCode::FORBIDDEN,
             UserError::ExpiredToken => StatusCode::FORBIDDEN,
             UserError::Forbidden => StatusCode::FORBIDDEN,
+            UserError::WrongTokenKind => StatusCode::FORBIDDEN,
+            UserError::SessionNotFound => StatusCode::NOT_FOUND,
+            UserError::RefreshTokenReused => StatusCode::FORBIDDEN,
+            UserError::AccountLocked { .. } => StatusCode::TOO_MANY_REQUESTS,
+            UserError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
             UserError::InvalidCredentials => StatusCode::BAD_REQUEST,
+            UserError::WeakPassword(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            UserError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            UserError::InvalidToken => StatusCode::BAD_REQUEST,
+            UserError::EmailNotVerified => StatusCode::FORBIDDEN,
+            UserError::MfaAlreadyEnabled => StatusCode::CONFLICT,
+            UserError::MfaNotEnrolled => StatusCode::BAD_REQUEST,
+            UserError::InvalidPasskey => StatusCode::BAD_REQUEST,
+            UserError::PasskeyNotFound => StatusCode::NOT_FOUND,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
     fn error_response(&self) -> HttpResponse<BoxBody> {
-        HttpResponse::build(self.status_code()).json(UserErrorResponse::from(self))
+        let mut response = HttpResponse::build(self.status_code());
+        if let Some(retry_after) = self.retry_after() {
+            response.insert_header((RETRY_AFTER, retry_after.to_string()));
+        }
+        response.json(UserErrorResponse::from(self))
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Validate)]
 #[serde(rename_all = "camelCase")]
 pub struct NewUser {
+    #[validate(
+        email(message = "Must be a valid email address."),
+        length(max = 200, message = "Must be at most 200 characters long.")
+    )]
     pub email: String,
     pub password: String,
+    #[validate(length(
+        min = 1,
+        max = 100,
+        message = "Must be between 1 and 100 characters long."
+    ))]
     pub nickname: String,
+    pub device_label: Option<String>,
+}
+
+impl NewUser {
+    pub fn normalize(&mut self) {
+        self.email = normalize_email(&self.email);
+        self.nickname = normalize_text(&self.nickname);
+    }
+}
+
+/// Trims and NFC-normalizes user-facing text so visually identical values are
+/// stored and compared the same way. Passwords are left untouched, as existing
+/// hashes were made from the raw input.
+pub fn normalize_text(value: &str) -> String {
+    value.trim().nfc().collect()
+}
+
+/// Emails are unique regardless of case, see the `rl_users_email_lower_key` index.
+pub fn normalize_email(value: &str) -> String {
+    normalize_text(value).to_lowercase()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub device_label: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn from_request(req: &HttpRequest, device_label: Option<String>) -> Self {
+        let user_agent = req
+            .headers()
+            .get(actix_web::http::header::USER_AGENT)
+            .and_then(|h| h.to_str().ok())
+            .map(|h| h.to_string());
+        let ip_address = client_ip::client_ip(req);
+        let device_label = device_label
+            .map(|label| label.trim().chars().take(100).collect::<String>())
+            .filter(|label| !label.is_empty());
+
+        Self {
+            user_agent,
+            ip_address,
+            device_label,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -98,16 +295,31 @@ pub struct TokenResponse {
 }
 #[post("/users/register")]
 pub async fn register_user(
+    req: HttpRequest,
     pool: web::Data<DbPool>,
+    mailer: web::Data<dyn Mailer>,
     new_user: web::Json<NewUser>,
 ) -> Result<HttpResponse> {
+    let mut new_user = new_user.into_inner();
+    new_user.normalize();
+    new_user.validate()?;
+    password::policy().check(
+        "password",
+        &new_user.password,
+        &new_user.email,
+        &new_user.nickname,
+    )?;
+    let client = ClientInfo::from_request(&req, new_user.device_label.clone());
+    let email = new_user.email.clone();
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
     let (new_user_id, new_connection_id) = web::block(move || {
-        let mut conn = pool.get().expect("Couldn't get db connection from pool.");
-        let new_user_id = db::save_new_user(&mut conn, new_user.into_inner());
+        let new_user_id = db::save_new_user(&mut conn, new_user);
         match new_user_id {
             Err(e) => (Err(e), None),
             Ok(user_id) => {
-                let new_connection_id = db::generate_new_connection(&mut conn, user_id);
+                let new_connection_id = db::generate_new_connection(&mut conn, user_id, client);
                 (new_user_id, Some(new_connection_id))
             }
         }
@@ -119,6 +331,19 @@ pub async fn register_user(
 
     let (access_token, refresh_token, expire_in) =
         auth::generate_tokens(new_user_id, new_connection_id, vec!["USER".to_string()])?;
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+    // The account exists either way, the user can ask for another link.
+    if let Err(e) = web::block(move || {
+        send_verification_email(&mut conn, mailer.get_ref(), new_user_id, &email)
+    })
+    .await?
+    {
+        log::warn!("Couldn't send verification email to {}: {}", new_user_id, e);
+    }
+
     Ok(HttpResponse::Ok().json(TokenResponse {
         expires_in: expire_in,
         access_token,
@@ -126,33 +351,478 @@ pub async fn register_user(
     }))
 }
 
+fn send_verification_email(
+    conn: &mut diesel::PgConnection,
+    mailer: &dyn Mailer,
+    user_id: Uuid,
+    email: &str,
+) -> Result<()> {
+    let verification = db::create_email_verification(conn, user_id, email)?;
+    let token = auth::generate_email_token(
+        auth::EMAIL_VERIFICATION_AUDIENCE,
+        verification.id_email_verification,
+        user_id,
+        email,
+        verification.expires_at,
+    )?;
+    mailer.send(&Email {
+        to: email.to_string(),
+        subject: "Verify your email address".to_string(),
+        body: format!(
+            "Confirm your email address by opening this link:\n\n{}\n\nThe link expires at {} UTC.",
+            app_link("/verify-email", &token)?,
+            verification.expires_at
+        ),
+    })?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
+
+#[post("/users/verify-email")]
+pub async fn verify_email(
+    pool: web::Data<DbPool>,
+    request: web::Json<VerifyEmailRequest>,
+) -> Result<HttpResponse> {
+    let claims = auth::get_email_token_claims(&request.token, auth::EMAIL_VERIFICATION_AUDIENCE)?;
+    let verification_id = claims.jti_uuid()?;
+    let user_id = claims.user_uuid()?;
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    web::block(move || db::verify_email(&mut conn, verification_id, user_id, &claims.email))
+        .await??;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+#[serde(rename_all = "camelCase")]
+pub struct ResendVerificationRequest {
+    #[validate(
+        email(message = "Must be a valid email address."),
+        length(max = 200, message = "Must be at most 200 characters long.")
+    )]
+    pub email: String,
+}
+
+/// Always answers 202 so the endpoint can't be used to probe for accounts.
+#[post("/users/verify-email/resend")]
+pub async fn resend_verification_email(
+    pool: web::Data<DbPool>,
+    mailer: web::Data<dyn Mailer>,
+    request: web::Json<ResendVerificationRequest>,
+) -> Result<HttpResponse> {
+    let mut request = request.into_inner();
+    request.email = normalize_email(&request.email);
+    request.validate()?;
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    web::block(move || {
+        let Some(user) = db::get_user_by_email(&mut conn, &request.email)? else {
+            return Ok(());
+        };
+        if user.email_verified_at.is_some() {
+            return Ok(());
+        }
+        if let Err(e) =
+            send_verification_email(&mut conn, mailer.get_ref(), user.id_user, &user.email)
+        {
+            log::warn!(
+                "Couldn't send verification email to {}: {}",
+                user.id_user,
+                e
+            );
+        }
+        Ok::<_, UserError>(())
+    })
+    .await??;
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+fn send_password_reset_email(
+    conn: &mut diesel::PgConnection,
+    mailer: &dyn Mailer,
+    user: &RLUser,
+) -> Result<()> {
+    let token = one_time_token::generate_token();
+    let reset = db::create_password_reset(conn, user.id_user, one_time_token::hash_token(&token))?;
+    mailer.send(&Email {
+        to: user.email.clone(),
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Choose a new password by opening this link:\n\n{}\n\nThe link expires at {} UTC. If you didn't ask for it, you can ignore this email.",
+            app_link("/reset-password", &token)?,
+            reset.expires_at
+        ),
+    })?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+#[serde(rename_all = "camelCase")]
+pub struct ForgotPasswordRequest {
+    #[validate(
+        email(message = "Must be a valid email address."),
+        length(max = 200, message = "Must be at most 200 characters long.")
+    )]
+    pub email: String,
+}
+
+/// Always answers 202 so the endpoint can't be used to probe for accounts.
+#[post("/users/password/forgot")]
+pub async fn forgot_password(
+    pool: web::Data<DbPool>,
+    mailer: web::Data<dyn Mailer>,
+    request: web::Json<ForgotPasswordRequest>,
+) -> Result<HttpResponse> {
+    let mut request = request.into_inner();
+    request.email = normalize_email(&request.email);
+    request.validate()?;
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    web::block(move || {
+        let Some(user) = db::get_user_by_email(&mut conn, &request.email)? else {
+            return Ok(());
+        };
+        if let Err(e) = send_password_reset_email(&mut conn, mailer.get_ref(), &user) {
+            log::warn!(
+                "Couldn't send password reset email to {}: {}",
+                user.id_user,
+                e
+            );
+        }
+        Ok::<_, UserError>(())
+    })
+    .await??;
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
+/// Sets the new password and signs the user out everywhere.
+#[post("/users/password/reset")]
+pub async fn reset_password(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    request: web::Json<ResetPasswordRequest>,
+) -> Result<HttpResponse> {
+    let client = ClientInfo::from_request(&req, None);
+    let request = request.into_inner();
+    let token_hash = one_time_token::hash_token(&request.token);
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    web::block(move || {
+        let user = db::get_password_reset_user(&mut conn, &token_hash)?;
+        password::policy().check(
+            "newPassword",
+            &request.new_password,
+            &user.email,
+            &user.nickname,
+        )?;
+        let password_hash = password::hashing().hash(&request.new_password)?;
+        db::reset_password(&mut conn, &token_hash, password_hash, client)?;
+        db::clear_failed_logins(&mut conn, &AttemptKey::account(&user.email))
+    })
+    .await??;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+    #[serde(default)]
+    pub end_other_sessions: bool,
+}
+
+/// Re-checks the password of a signed-in user before a sensitive change. Wrong
+/// passwords count towards the account lockout like failed logins.
+fn confirm_current_password(
+    conn: &mut diesel::PgConnection,
+    user: &RLUser,
+    current_password: &str,
+) -> Result<()> {
+    let attempt_keys = [AttemptKey::account(&user.email)];
+    db::check_login_lock(conn, &attempt_keys)?;
+    if let Err(e) =
+        password::check_password(password::hashing(), current_password, Some(&user.password))
+    {
+        db::record_failed_login(conn, &attempt_keys)?;
+        return Err(e);
+    }
+    db::clear_failed_logins(conn, &attempt_keys[0])
+}
+
+#[put("/password")]
+pub async fn change_password(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<TokenClaims>,
+    request: web::Json<ChangePasswordRequest>,
+) -> Result<HttpResponse> {
+    let client = ClientInfo::from_request(&req, None);
+    let user_id = claims.user_uuid()?;
+    let connection_id = claims.connection_uuid()?;
+    let request = request.into_inner();
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    web::block(move || {
+        let user = db::get_user(&mut conn, user_id)?;
+        confirm_current_password(&mut conn, &user, &request.current_password)?;
+        password::policy().check(
+            "newPassword",
+            &request.new_password,
+            &user.email,
+            &user.nickname,
+        )?;
+
+        let password_hash = password::hashing().hash(&request.new_password)?;
+        let keep_connection = request.end_other_sessions.then_some(connection_id);
+        db::change_password(&mut conn, user_id, password_hash, keep_connection, client)
+    })
+    .await??;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeEmailRequest {
+    #[validate(
+        email(message = "Must be a valid email address."),
+        length(max = 200, message = "Must be at most 200 characters long.")
+    )]
+    pub new_email: String,
+    pub current_password: String,
+}
+
+/// Mails a confirmation link to the new address and a cancel link to the old one.
+/// Nothing changes until the new address is confirmed.
+#[post("/email")]
+pub async fn change_email(
+    pool: web::Data<DbPool>,
+    mailer: web::Data<dyn Mailer>,
+    claims: web::ReqData<TokenClaims>,
+    request: web::Json<ChangeEmailRequest>,
+) -> Result<HttpResponse> {
+    let user_id = claims.user_uuid()?;
+    let mut request = request.into_inner();
+    request.new_email = normalize_email(&request.new_email);
+    request.validate()?;
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    web::block(move || {
+        let user = db::get_user(&mut conn, user_id)?;
+        confirm_current_password(&mut conn, &user, &request.current_password)?;
+        if request.new_email == normalize_email(&user.email) {
+            return Err(UserError::Validation(vec![FieldError::new(
+                "newEmail",
+                "Must differ from your current email.",
+            )]));
+        }
+
+        let confirm_token = one_time_token::generate_token();
+        let cancel_token = one_time_token::generate_token();
+        let change = db::create_email_change(
+            &mut conn,
+            &user,
+            &request.new_email,
+            one_time_token::hash_token(&confirm_token),
+            one_time_token::hash_token(&cancel_token),
+        )?;
+        mailer.send(&Email {
+            to: change.new_email.clone(),
+            subject: "Confirm your new email address".to_string(),
+            body: format!(
+                "Confirm that you want to use this address for your account by opening this link:\n\n{}\n\nThe link expires at {} UTC.",
+                app_link("/confirm-email-change", &confirm_token)?,
+                change.expires_at
+            ),
+        })?;
+        mailer.send(&Email {
+            to: change.old_email.clone(),
+            subject: "Your email address is being changed".to_string(),
+            body: format!(
+                "Someone asked to change the email address of your account to {}. If it wasn't you, cancel the change and sign out everywhere by opening this link:\n\n{}\n\nThe link works until {} UTC.",
+                change.new_email,
+                app_link("/cancel-email-change", &cancel_token)?,
+                change.expires_at
+            ),
+        })?;
+        Ok(())
+    })
+    .await??;
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailChangeTokenRequest {
+    pub token: String,
+}
+
+#[post("/users/email/confirm")]
+pub async fn confirm_email_change(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    request: web::Json<EmailChangeTokenRequest>,
+) -> Result<HttpResponse> {
+    let client = ClientInfo::from_request(&req, None);
+    let token_hash = one_time_token::hash_token(&request.token);
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    web::block(move || db::confirm_email_change(&mut conn, &token_hash, client)).await??;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/users/email/cancel")]
+pub async fn cancel_email_change(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    request: web::Json<EmailChangeTokenRequest>,
+) -> Result<HttpResponse> {
+    let client = ClientInfo::from_request(&req, None);
+    let token_hash = one_time_token::hash_token(&request.token);
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    web::block(move || db::cancel_email_change(&mut conn, &token_hash, client)).await??;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+#[serde(rename_all = "camelCase")]
 pub struct LoginRequest {
+    #[validate(
+        email(message = "Must be a valid email address."),
+        length(max = 200, message = "Must be at most 200 characters long.")
+    )]
     pub email: String,
+    #[validate(length(min = 1, message = "Must not be empty."))]
     pub password: String,
+    pub device_label: Option<String>,
+}
+
+impl LoginRequest {
+    pub fn normalize(&mut self) {
+        self.email = normalize_email(&self.email);
+    }
 }
 
 #[post("/users/login")]
 pub async fn login(
+    req: HttpRequest,
     pool: web::Data<DbPool>,
     login_request: web::Json<LoginRequest>,
 ) -> Result<HttpResponse> {
+    let mut login_request = login_request.into_inner();
+    login_request.normalize();
+    login_request.validate()?;
+    let client = ClientInfo::from_request(&req, login_request.device_label.clone());
     let mut conn = pool
         .get()
         .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
-    let email = login_request.email.clone();
-    let password = login_request.password.clone();
-    let (user, role) = web::block(move || db::login(&mut conn, email, password)).await??;
+    let email = login_request.email;
+    let password = login_request.password;
+    let attempt_keys = lockout::attempt_keys(&email, client.ip_address.as_deref());
+    let (user, role, mfa_enabled) = web::block(move || {
+        db::check_login_lock(&mut conn, &attempt_keys)?;
+        match db::login(&mut conn, email, password) {
+            Err(UserError::InvalidCredentials) => {
+                db::record_failed_login(&mut conn, &attempt_keys)?;
+                Err(UserError::InvalidCredentials)
+            }
+            Ok((user, role)) => {
+                let mfa_enabled = db::get_confirmed_totp(&mut conn, user.id_user)?.is_some();
+                if let Some(key) = lockout::cleared_by_first_factor(&attempt_keys, mfa_enabled) {
+                    db::clear_failed_logins(&mut conn, key)?;
+                }
+                Ok((user, role, mfa_enabled))
+            }
+            Err(e) => Err(e),
+        }
+    })
+    .await??;
+
+    finish_first_factor(&pool, user.id_user, role.description, mfa_enabled, client).await
+}
+
+/// Logins that only proved the first factor stop here when the account has a
+/// second one.
+async fn finish_first_factor(
+    pool: &web::Data<DbPool>,
+    user_id: Uuid,
+    role: String,
+    mfa_enabled: bool,
+    client: ClientInfo,
+) -> Result<HttpResponse> {
+    if mfa_enabled {
+        let (mfa_token, expires_in) = auth::generate_mfa_token(user_id)?;
+        return Ok(HttpResponse::Ok().json(MfaChallengeResponse {
+            mfa_required: true,
+            mfa_token,
+            expires_in,
+        }));
+    }
 
+    start_session(pool, user_id, role, client).await
+}
+
+/// Every way of signing in ends here once the user is fully authenticated.
+async fn start_session(
+    pool: &web::Data<DbPool>,
+    user_id: Uuid,
+    role: String,
+    client: ClientInfo,
+) -> Result<HttpResponse> {
     let mut conn = pool
         .get()
         .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
 
-    let user_id = user.id_user;
-    let connection = web::block(move || db::generate_new_connection(&mut conn, user_id)).await??;
+    let connection =
+        web::block(move || db::generate_new_connection(&mut conn, user_id, client)).await??;
 
     let (access_token, refresh_token, expire_in) =
-        auth::generate_tokens(user_id, connection, vec![role.description])?;
+        auth::generate_tokens(user_id, connection, vec![role])?;
     Ok(HttpResponse::Ok().json(TokenResponse {
         expires_in: expire_in,
         access_token,
@@ -160,17 +830,620 @@ pub async fn login(
     }))
 }
 
+/// Returned by `/users/login` instead of tokens when the account has a second
+/// factor; the token is exchanged at `/users/login/mfa`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MfaChallengeResponse {
+    pub mfa_required: bool,
+    pub mfa_token: String,
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MfaLoginRequest {
+    pub mfa_token: String,
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+    pub device_label: Option<String>,
+}
+
+/// Wrong codes count towards the same lockout as wrong passwords.
+#[post("/users/login/mfa")]
+pub async fn login_mfa(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    request: web::Json<MfaLoginRequest>,
+) -> Result<HttpResponse> {
+    let request = request.into_inner();
+    let client = ClientInfo::from_request(&req, request.device_label.clone());
+    let claims = auth::get_claims_and_validate(request.mfa_token, auth::TokenKind::Mfa)?;
+    let user_id = claims.user_uuid()?;
+    let ip_address = client.ip_address.clone();
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    let role = web::block(move || {
+        let user = db::get_user(&mut conn, user_id)?;
+        let attempt_keys = lockout::attempt_keys(&user.email, ip_address.as_deref());
+        db::check_login_lock(&mut conn, &attempt_keys)?;
+
+        let verified = match (request.code, request.recovery_code) {
+            (Some(code), _) => match db::get_confirmed_totp(&mut conn, user_id)? {
+                Some(totp) => {
+                    let now = chrono::Utc::now().timestamp() as u64;
+                    match mfa::verify_code(&totp.secret, &code, totp.last_used_step, now) {
+                        Some(step) => db::use_totp_step(&mut conn, user_id, step)?,
+                        None => false,
+                    }
+                }
+                None => false,
+            },
+            (None, Some(recovery_code)) => {
+                db::use_recovery_code(&mut conn, user_id, &mfa::hash_recovery_code(&recovery_code))?
+            }
+            (None, None) => false,
+        };
+        if !verified {
+            db::record_failed_login(&mut conn, &attempt_keys)?;
+            return Err(UserError::InvalidCredentials);
+        }
+        db::clear_failed_logins(&mut conn, &attempt_keys[0])?;
+        db::get_role_by_user_id(&mut conn, user_id.to_string())
+    })
+    .await??;
+
+    start_session(&pool, user_id, role.description, client).await
+}
+
+fn send_login_code_email(
+    conn: &mut diesel::PgConnection,
+    mailer: &dyn Mailer,
+    user: &RLUser,
+) -> Result<()> {
+    let code = one_time_token::generate_code();
+    let login_code = db::create_login_code(conn, user.id_user, &code)?;
+    mailer.send(&Email {
+        to: user.email.clone(),
+        subject: "Your sign-in code".to_string(),
+        body: format!(
+            "Your sign-in code is {}\n\nThe code expires at {} UTC. If you didn't try to sign in, you can ignore this email.",
+            code,
+            login_code.expires_at
+        ),
+    })?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+#[serde(rename_all = "camelCase")]
+pub struct LoginCodeRequest {
+    #[validate(
+        email(message = "Must be a valid email address."),
+        length(max = 200, message = "Must be at most 200 characters long.")
+    )]
+    pub email: String,
+}
+
+/// Always answers 202 so the endpoint can't be used to probe for accounts.
+#[post("/users/login/code/request")]
+pub async fn request_login_code(
+    pool: web::Data<DbPool>,
+    mailer: web::Data<dyn Mailer>,
+    request: web::Json<LoginCodeRequest>,
+) -> Result<HttpResponse> {
+    let mut request = request.into_inner();
+    request.email = normalize_email(&request.email);
+    request.validate()?;
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    web::block(move || {
+        let Some(user) = db::get_user_by_email(&mut conn, &request.email)? else {
+            return Ok(());
+        };
+        if let Err(e) = send_login_code_email(&mut conn, mailer.get_ref(), &user) {
+            log::warn!("Couldn't send login code email to {}: {}", user.id_user, e);
+        }
+        Ok::<_, UserError>(())
+    })
+    .await??;
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyLoginCodeRequest {
+    #[validate(
+        email(message = "Must be a valid email address."),
+        length(max = 200, message = "Must be at most 200 characters long.")
+    )]
+    pub email: String,
+    #[validate(length(min = 1, message = "Must not be empty."))]
+    pub code: String,
+    pub device_label: Option<String>,
+}
+
+/// Takes the place of the password, so wrong codes count towards the lockout and
+/// accounts with TOTP still get asked for it.
+#[post("/users/login/code/verify")]
+pub async fn verify_login_code(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    request: web::Json<VerifyLoginCodeRequest>,
+) -> Result<HttpResponse> {
+    let mut request = request.into_inner();
+    request.email = normalize_email(&request.email);
+    request.validate()?;
+    let client = ClientInfo::from_request(&req, request.device_label.clone());
+    let attempt_keys = lockout::attempt_keys(&request.email, client.ip_address.as_deref());
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    let (user_id, role, mfa_enabled) = web::block(move || {
+        db::check_login_lock(&mut conn, &attempt_keys)?;
+        let user = db::get_user_by_email(&mut conn, &request.email)?;
+        let verified = match &user {
+            Some(user) => db::use_login_code(&mut conn, user.id_user, &request.code)?,
+            None => false,
+        };
+        let Some(user) = user.filter(|_| verified) else {
+            db::record_failed_login(&mut conn, &attempt_keys)?;
+            return Err(UserError::InvalidCredentials);
+        };
+        let mfa_enabled = db::get_confirmed_totp(&mut conn, user.id_user)?.is_some();
+        if let Some(key) = lockout::cleared_by_first_factor(&attempt_keys, mfa_enabled) {
+            db::clear_failed_logins(&mut conn, key)?;
+        }
+        let role = db::get_role_by_user_id(&mut conn, user.id_user.to_string())?;
+        Ok((user.id_user, role, mfa_enabled))
+    })
+    .await??;
+
+    finish_first_factor(&pool, user_id, role.description, mfa_enabled, client).await
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CurrentPasswordRequest {
+    pub current_password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TotpEnrollmentResponse {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+/// Starts an enrollment that only takes effect once a code from the app is
+/// confirmed.
+#[post("/mfa/totp")]
+pub async fn enroll_totp(
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<TokenClaims>,
+    request: web::Json<CurrentPasswordRequest>,
+) -> Result<HttpResponse> {
+    let user_id = claims.user_uuid()?;
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    let enrollment = web::block(move || {
+        let user = db::get_user(&mut conn, user_id)?;
+        confirm_current_password(&mut conn, &user, &request.current_password)?;
+        let secret = mfa::generate_secret();
+        db::start_totp_enrollment(&mut conn, user_id, secret.clone())?;
+        Ok::<_, UserError>(TotpEnrollmentResponse {
+            otpauth_uri: mfa::otpauth_uri(&secret, &user.email)?,
+            secret,
+        })
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(enrollment))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfirmTotpRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+/// The recovery codes are only ever shown in this response.
+#[post("/mfa/totp/confirm")]
+pub async fn confirm_totp(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<TokenClaims>,
+    request: web::Json<ConfirmTotpRequest>,
+) -> Result<HttpResponse> {
+    let client = ClientInfo::from_request(&req, None);
+    let user_id = claims.user_uuid()?;
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    let recovery_codes = web::block(move || {
+        let totp = db::get_pending_totp(&mut conn, user_id)?;
+        let now = chrono::Utc::now().timestamp() as u64;
+        let step = mfa::verify_code(&totp.secret, &request.code, None, now)
+            .ok_or(UserError::InvalidCredentials)?;
+        let recovery_codes = mfa::generate_recovery_codes();
+        db::confirm_totp(
+            &mut conn,
+            user_id,
+            step,
+            recovery_codes
+                .iter()
+                .map(|code| mfa::hash_recovery_code(code))
+                .collect(),
+            client,
+        )?;
+        Ok::<_, UserError>(recovery_codes)
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(RecoveryCodesResponse { recovery_codes }))
+}
+
+#[post("/mfa/totp/disable")]
+pub async fn disable_totp(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<TokenClaims>,
+    request: web::Json<CurrentPasswordRequest>,
+) -> Result<HttpResponse> {
+    let client = ClientInfo::from_request(&req, None);
+    let user_id = claims.user_uuid()?;
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    web::block(move || {
+        let user = db::get_user(&mut conn, user_id)?;
+        confirm_current_password(&mut conn, &user, &request.current_password)?;
+        db::disable_totp(&mut conn, user_id, client)
+    })
+    .await??;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PasskeyChallengeResponse {
+    pub challenge_id: Uuid,
+    pub public_key: serde_json::Value,
+}
+
+/// A `PublicKeyCredential` as the browser returns it, binary fields base64url encoded.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PasskeyCredential<R> {
+    pub id: String,
+    pub response: R,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    pub user_handle: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PasskeyRegistrationRequest {
+    pub challenge_id: Uuid,
+    pub label: Option<String>,
+    pub credential: PasskeyCredential<AttestationResponse>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PasskeyLoginRequest {
+    pub challenge_id: Uuid,
+    pub credential: PasskeyCredential<AssertionResponse>,
+    pub device_label: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PasskeyResponse {
+    pub id: Uuid,
+    pub label: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+impl From<WebauthnCredential> for PasskeyResponse {
+    fn from(value: WebauthnCredential) -> Self {
+        Self {
+            id: value.id_webauthn_credential,
+            label: value.label,
+            created_at: value.created_at,
+            last_used_at: value.last_used_at,
+        }
+    }
+}
+
+/// Like enrolling TOTP, adding a passkey needs the password so a stolen access
+/// token can't be turned into a lasting way in.
+#[post("/passkeys/register/start")]
+pub async fn start_passkey_registration(
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<TokenClaims>,
+    request: web::Json<CurrentPasswordRequest>,
+) -> Result<HttpResponse> {
+    let user_id = claims.user_uuid()?;
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    let response = web::block(move || {
+        let user = db::get_user(&mut conn, user_id)?;
+        confirm_current_password(&mut conn, &user, &request.current_password)?;
+        let registered_credential_ids = db::get_webauthn_credentials(&mut conn, user_id)?
+            .into_iter()
+            .map(|credential| credential.credential_id)
+            .collect::<Vec<_>>();
+        let challenge =
+            db::create_webauthn_challenge(&mut conn, Some(user_id), webauthn::REGISTRATION)?;
+        Ok::<_, UserError>(PasskeyChallengeResponse {
+            challenge_id: challenge.id_webauthn_challenge,
+            public_key: webauthn::relying_party().creation_options(
+                &challenge.challenge,
+                user_id,
+                &user.email,
+                &user.nickname,
+                &registered_credential_ids,
+            ),
+        })
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[post("/passkeys/register/finish")]
+pub async fn finish_passkey_registration(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<TokenClaims>,
+    request: web::Json<PasskeyRegistrationRequest>,
+) -> Result<HttpResponse> {
+    let client = ClientInfo::from_request(&req, None);
+    let user_id = claims.user_uuid()?;
+    let request = request.into_inner();
+    let label = request
+        .label
+        .map(|label| normalize_text(&label).chars().take(100).collect::<String>())
+        .filter(|label| !label.is_empty());
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    let credential = web::block(move || {
+        let challenge = db::take_webauthn_challenge(
+            &mut conn,
+            request.challenge_id,
+            Some(user_id),
+            webauthn::REGISTRATION,
+        )?;
+        let response = request.credential.response;
+        let registered = webauthn::relying_party().verify_registration(
+            &challenge.challenge,
+            &response.client_data_json,
+            &response.attestation_object,
+        )?;
+        if registered.credential_id != request.credential.id.trim_end_matches('=') {
+            return Err(UserError::InvalidPasskey);
+        }
+        db::save_webauthn_credential(&mut conn, user_id, registered, label, client)
+    })
+    .await??;
+
+    Ok(HttpResponse::Created().json(PasskeyResponse::from(credential)))
+}
+
+#[get("/passkeys")]
+pub async fn list_passkeys(
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<TokenClaims>,
+) -> Result<HttpResponse> {
+    let user_id = claims.user_uuid()?;
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    let credentials =
+        web::block(move || db::get_webauthn_credentials(&mut conn, user_id)).await??;
+
+    Ok(HttpResponse::Ok().json(
+        credentials
+            .into_iter()
+            .map(PasskeyResponse::from)
+            .collect::<Vec<_>>(),
+    ))
+}
+
+#[delete("/passkeys/{id}")]
+pub async fn delete_passkey(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<TokenClaims>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse> {
+    let client = ClientInfo::from_request(&req, None);
+    let user_id = claims.user_uuid()?;
+    let credential_id = path.into_inner();
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    web::block(move || db::delete_webauthn_credential(&mut conn, user_id, credential_id, client))
+        .await??;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Passkeys are discoverable, so the login starts without knowing who the user is.
+#[post("/users/login/passkey/start")]
+pub async fn start_passkey_login(pool: web::Data<DbPool>) -> Result<HttpResponse> {
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    let challenge = web::block(move || {
+        db::create_webauthn_challenge(&mut conn, None, webauthn::AUTHENTICATION)
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(PasskeyChallengeResponse {
+        challenge_id: challenge.id_webauthn_challenge,
+        public_key: webauthn::relying_party().request_options(&challenge.challenge),
+    }))
+}
+
+/// A passkey whose authenticator verified the user with a PIN or biometric is
+/// already two factors and skips the TOTP step; one that was only touched isn't.
+#[post("/users/login/passkey/finish")]
+pub async fn finish_passkey_login(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    request: web::Json<PasskeyLoginRequest>,
+) -> Result<HttpResponse> {
+    let request = request.into_inner();
+    let client = ClientInfo::from_request(&req, request.device_label.clone());
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    let ip_address = client.ip_address.clone();
+    let (user_id, role, mfa_enabled) = web::block(move || {
+        let challenge = db::take_webauthn_challenge(
+            &mut conn,
+            request.challenge_id,
+            None,
+            webauthn::AUTHENTICATION,
+        )?;
+        // Without a known credential there is no account to count against, only
+        // the address trying it.
+        let credential = match db::get_webauthn_credential(
+            &mut conn,
+            request.credential.id.trim_end_matches('='),
+        ) {
+            Err(UserError::InvalidPasskey) => {
+                let ip_keys = ip_address.as_deref().map(AttemptKey::ip);
+                db::check_login_lock(&mut conn, ip_keys.as_slice())?;
+                db::record_failed_login(&mut conn, ip_keys.as_slice())?;
+                return Err(UserError::InvalidPasskey);
+            }
+            credential => credential?,
+        };
+        let user = db::get_user(&mut conn, credential.id_user)?;
+        let attempt_keys = lockout::attempt_keys(&user.email, ip_address.as_deref());
+        db::check_login_lock(&mut conn, &attempt_keys)?;
+
+        let response = request.credential.response;
+        let verified = if response.user_handle.as_deref().is_some_and(|user_handle| {
+            user_handle.trim_end_matches('=') != webauthn::user_handle(credential.id_user)
+        }) {
+            Err(UserError::InvalidPasskey)
+        } else {
+            webauthn::relying_party()
+                .verify_assertion(
+                    &challenge.challenge,
+                    &response.client_data_json,
+                    &response.authenticator_data,
+                    &response.signature,
+                    &credential.public_key,
+                    credential.sign_count,
+                )
+                .and_then(|assertion| {
+                    match db::use_webauthn_credential(&mut conn, &credential, assertion.sign_count)?
+                    {
+                        true => Ok(assertion),
+                        false => Err(UserError::InvalidPasskey),
+                    }
+                })
+        };
+        let assertion = match verified {
+            Err(UserError::InvalidPasskey) => {
+                db::record_failed_login(&mut conn, &attempt_keys)?;
+                return Err(UserError::InvalidPasskey);
+            }
+            assertion => assertion?,
+        };
+        let mfa_enabled = !assertion.user_verified
+            && db::get_confirmed_totp(&mut conn, credential.id_user)?.is_some();
+        if let Some(key) = lockout::cleared_by_first_factor(&attempt_keys, mfa_enabled) {
+            db::clear_failed_logins(&mut conn, key)?;
+        }
+        let role = db::get_role_by_user_id(&mut conn, credential.id_user.to_string())?;
+        Ok((credential.id_user, role, mfa_enabled))
+    })
+    .await??;
+
+    finish_first_factor(&pool, user_id, role.description, mfa_enabled, client).await
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RefreshAuthRequest {
     pub refresh_token: String,
+    pub device_label: Option<String>,
 }
 #[post("/users/token")]
 pub async fn refresh_auth(
+    req: HttpRequest,
     pool: web::Data<DbPool>,
     refresh_auth_request: web::Json<RefreshAuthRequest>,
 ) -> Result<HttpResponse> {
-    let claims = auth::get_claims_and_validate(refresh_auth_request.refresh_token.clone())?;
+    let client = ClientInfo::from_request(&req, refresh_auth_request.device_label.clone());
+    let claims = auth::get_claims_and_validate(
+        refresh_auth_request.refresh_token.clone(),
+        auth::TokenKind::Refresh,
+    )?;
+    // Tokens granted to an OAuth client are refreshed through `/oauth/token`, so they
+    // keep their client and scope.
+    if claims.client_id.is_some() {
+        return Err(UserError::WrongTokenKind);
+    }
     let user_id = Uuid::from_str(&claims.user_id).map_err(|e| anyhow!("{}", e))?;
     let connection_id = Uuid::from_str(&claims.connection_id).map_err(|e| anyhow!("{}", e))?;
 
@@ -178,21 +1451,16 @@ pub async fn refresh_auth(
         .get()
         .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
 
-    web::block(move || db::validate_connection(&mut conn, user_id, connection_id)).await??;
-
-    let mut conn = pool
-        .get()
-        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
-
-    let role = web::block(move || db::get_role_by_user_id(&mut conn, claims.user_id.clone()))
-        .await??;
+    let role =
+        web::block(move || db::get_role_by_user_id(&mut conn, claims.user_id.clone())).await??;
 
     let mut conn = pool
         .get()
         .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
 
-    let connection = web::block(move || db::generate_new_connection(&mut conn, user_id))
-        .await??;
+    let connection =
+        web::block(move || db::rotate_connection(&mut conn, user_id, connection_id, client))
+            .await??;
 
     let (access_token, refresh_token, expire_in) =
         auth::generate_tokens(user_id, connection, vec![role.description])?;
@@ -202,3 +1470,155 @@ pub async fn refresh_auth(
         refresh_token,
     }))
 }
+
+#[post("")]
+pub async fn logout(
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<TokenClaims>,
+) -> Result<HttpResponse> {
+    let user_id = claims.user_uuid()?;
+    let connection_id = claims.connection_uuid()?;
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    web::block(move || db::end_connection(&mut conn, user_id, connection_id)).await??;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("")]
+pub async fn logout_all(
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<TokenClaims>,
+) -> Result<HttpResponse> {
+    let user_id = claims.user_uuid()?;
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    web::block(move || db::end_all_connections(&mut conn, user_id)).await??;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionResponse {
+    pub id: Uuid,
+    pub device_label: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub connect_at: Option<NaiveDateTime>,
+    pub last_refresh_at: Option<NaiveDateTime>,
+    pub current: bool,
+}
+
+#[get("/sessions")]
+pub async fn list_sessions(
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<TokenClaims>,
+) -> Result<HttpResponse> {
+    let user_id = claims.user_uuid()?;
+    let connection_id = claims.connection_uuid()?;
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    let sessions = web::block(move || db::get_active_connections(&mut conn, user_id)).await??;
+
+    Ok(HttpResponse::Ok().json(
+        sessions
+            .into_iter()
+            .map(|session| SessionResponse {
+                id: session.id_session,
+                device_label: session.device_label,
+                user_agent: session.user_agent,
+                ip_address: session.ip_address,
+                connect_at: session.connect_at,
+                last_refresh_at: session.refreshed_at,
+                current: session.id_connection == connection_id,
+            })
+            .collect::<Vec<_>>(),
+    ))
+}
+
+#[delete("/sessions/{id}")]
+pub async fn revoke_session(
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<TokenClaims>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse> {
+    let user_id = claims.user_uuid()?;
+    let session_id = path.into_inner();
+
+    let mut conn = pool
+        .get()
+        .map_err(|_| anyhow!("Couldn't get db connection from pool."))?;
+
+    web::block(move || db::end_session(&mut conn, user_id, session_id)).await??;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_user(email: &str, nickname: &str) -> NewUser {
+        NewUser {
+            email: email.to_string(),
+            password: "Battery-Staple-9".to_string(),
+            nickname: nickname.to_string(),
+            device_label: None,
+        }
+    }
+
+    #[test]
+    fn trims_and_normalizes_before_validating() {
+        let mut user = new_user("  Jose@RL.com ", " Jose\u{301} ");
+
+        user.normalize();
+
+        assert_eq!(user.email, "jose@rl.com");
+        assert_eq!(user.nickname, "Jos\u{e9}");
+        assert!(user.validate().is_ok());
+    }
+
+    #[test]
+    fn reports_invalid_fields_with_a_422() {
+        let mut user = new_user("not-an-email", &"n".repeat(101));
+        user.normalize();
+
+        let error = UserError::from(user.validate().unwrap_err());
+
+        assert_eq!(error.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        let UserError::Validation(errors) = error else {
+            panic!("expected a validation error");
+        };
+        assert_eq!(
+            errors,
+            vec![
+                FieldError::new("email", "Must be a valid email address."),
+                FieldError::new("nickname", "Must be between 1 and 100 characters long."),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_fields_by_their_json_name() {
+        let request = ChangeEmailRequest {
+            new_email: "nope".to_string(),
+            current_password: "Battery-Staple-9".to_string(),
+        };
+
+        let UserError::Validation(errors) = UserError::from(request.validate().unwrap_err()) else {
+            panic!("expected a validation error");
+        };
+
+        assert_eq!(errors[0].field, "newEmail");
+    }
+}
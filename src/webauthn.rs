@@ -0,0 +1,501 @@
+use std::env;
+use std::sync::OnceLock;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ciborium::Value;
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use p256::PublicKey;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::users::UserError;
+
+/// COSE identifier of ES256, the only algorithm we offer and accept.
+pub const ES256: i64 = -7;
+
+pub const REGISTRATION: &str = "registration";
+pub const AUTHENTICATION: &str = "authentication";
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelyingParty {
+    pub id: String,
+    pub name: String,
+    pub origins: Vec<String>,
+    pub require_user_verification: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisteredCredential {
+    pub credential_id: String,
+    /// Uncompressed SEC1 point of the P-256 key.
+    pub public_key: Vec<u8>,
+    pub sign_count: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VerifiedAssertion {
+    pub sign_count: i64,
+    /// The authenticator checked a PIN or biometric, not just that someone touched it.
+    pub user_verified: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ceremony: String,
+    challenge: String,
+    origin: String,
+}
+
+struct AuthenticatorData<'a> {
+    rp_id_hash: &'a [u8],
+    flags: u8,
+    sign_count: u32,
+    attested_credential: &'a [u8],
+}
+
+impl RelyingParty {
+    pub fn from_env() -> Self {
+        Self {
+            id: env::var("WEBAUTHN_RP_ID").expect("WEBAUTHN_RP_ID must be set."),
+            name: env::var("WEBAUTHN_RP_NAME").expect("WEBAUTHN_RP_NAME must be set."),
+            origins: env::var("WEBAUTHN_ORIGINS")
+                .expect("WEBAUTHN_ORIGINS must be set.")
+                .split(',')
+                .map(|origin| origin.trim().to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect(),
+            require_user_verification: env::var("WEBAUTHN_REQUIRE_USER_VERIFICATION")
+                .map(|value| {
+                    value
+                        .parse()
+                        .expect("WEBAUTHN_REQUIRE_USER_VERIFICATION must be true or false.")
+                })
+                .unwrap_or(false),
+        }
+    }
+
+    /// `publicKey` options for `navigator.credentials.create()`. Passkeys are
+    /// discoverable so the user can later sign in without typing an email.
+    pub fn creation_options(
+        &self,
+        challenge: &str,
+        user_id: Uuid,
+        email: &str,
+        nickname: &str,
+        registered_credential_ids: &[String],
+    ) -> serde_json::Value {
+        json!({
+            "challenge": challenge,
+            "rp": { "id": self.id, "name": self.name },
+            "user": { "id": user_handle(user_id), "name": email, "displayName": nickname },
+            "pubKeyCredParams": [{ "type": "public-key", "alg": ES256 }],
+            "excludeCredentials": registered_credential_ids
+                .iter()
+                .map(|id| json!({ "type": "public-key", "id": id }))
+                .collect::<Vec<_>>(),
+            "authenticatorSelection": {
+                "residentKey": "required",
+                "userVerification": self.user_verification(),
+            },
+            "attestation": "none",
+        })
+    }
+
+    /// `publicKey` options for `navigator.credentials.get()`.
+    pub fn request_options(&self, challenge: &str) -> serde_json::Value {
+        json!({
+            "challenge": challenge,
+            "rpId": self.id,
+            "userVerification": self.user_verification(),
+        })
+    }
+
+    /// Checks a `navigator.credentials.create()` response. Attestation statements
+    /// are not verified, as we ask for `none` and only need the key itself.
+    pub fn verify_registration(
+        &self,
+        challenge: &str,
+        client_data_json: &str,
+        attestation_object: &str,
+    ) -> Result<RegisteredCredential, UserError> {
+        self.verify_client_data(client_data_json, "webauthn.create", challenge)?;
+
+        let attestation: Value = ciborium::from_reader(decode(attestation_object)?.as_slice())
+            .map_err(|_| UserError::InvalidPasskey)?;
+        let auth_data = attestation
+            .as_map()
+            .and_then(|entries| {
+                entries
+                    .iter()
+                    .find(|(key, _)| key.as_text() == Some("authData"))
+            })
+            .and_then(|(_, value)| value.as_bytes())
+            .ok_or(UserError::InvalidPasskey)?;
+        let auth_data = self.verify_authenticator_data(auth_data)?;
+        if auth_data.flags & FLAG_ATTESTED_CREDENTIAL_DATA == 0 {
+            return Err(UserError::InvalidPasskey);
+        }
+
+        // aaguid (16 bytes), credential id length (2 bytes), credential id, COSE key.
+        let attested = auth_data.attested_credential;
+        let id_length = attested
+            .get(16..18)
+            .map(|length| u16::from_be_bytes([length[0], length[1]]) as usize)
+            .ok_or(UserError::InvalidPasskey)?;
+        let credential_id = attested
+            .get(18..18 + id_length)
+            .ok_or(UserError::InvalidPasskey)?;
+        let cose_key: Value = ciborium::from_reader(&attested[18 + id_length..])
+            .map_err(|_| UserError::InvalidPasskey)?;
+
+        Ok(RegisteredCredential {
+            credential_id: URL_SAFE_NO_PAD.encode(credential_id),
+            public_key: es256_public_key(&cose_key)?,
+            sign_count: auth_data.sign_count as i64,
+        })
+    }
+
+    /// Checks a `navigator.credentials.get()` response against the stored key and
+    /// returns the new signature counter and whether the user was verified. A
+    /// counter that doesn't move forward means the authenticator may have been
+    /// cloned.
+    pub fn verify_assertion(
+        &self,
+        challenge: &str,
+        client_data_json: &str,
+        authenticator_data: &str,
+        signature: &str,
+        public_key: &[u8],
+        stored_sign_count: i64,
+    ) -> Result<VerifiedAssertion, UserError> {
+        self.verify_client_data(client_data_json, "webauthn.get", challenge)?;
+        let raw_auth_data = decode(authenticator_data)?;
+        let auth_data = self.verify_authenticator_data(&raw_auth_data)?;
+
+        let verifying_key =
+            VerifyingKey::from_sec1_bytes(public_key).map_err(|_| UserError::InvalidPasskey)?;
+        let signature =
+            Signature::from_der(&decode(signature)?).map_err(|_| UserError::InvalidPasskey)?;
+        let mut signed = raw_auth_data.clone();
+        signed.extend_from_slice(&Sha256::digest(decode(client_data_json)?));
+        verifying_key
+            .verify(&signed, &signature)
+            .map_err(|_| UserError::InvalidPasskey)?;
+
+        let sign_count = auth_data.sign_count as i64;
+        if (sign_count != 0 || stored_sign_count != 0) && sign_count <= stored_sign_count {
+            log::warn!(
+                "Passkey signature counter went from {} to {}, possible cloned authenticator.",
+                stored_sign_count,
+                sign_count
+            );
+            return Err(UserError::InvalidPasskey);
+        }
+        Ok(VerifiedAssertion {
+            sign_count,
+            user_verified: auth_data.flags & FLAG_USER_VERIFIED != 0,
+        })
+    }
+
+    fn user_verification(&self) -> &'static str {
+        if self.require_user_verification {
+            "required"
+        } else {
+            "preferred"
+        }
+    }
+
+    fn verify_client_data(
+        &self,
+        client_data_json: &str,
+        ceremony: &str,
+        challenge: &str,
+    ) -> Result<(), UserError> {
+        let client_data: ClientData = serde_json::from_slice(&decode(client_data_json)?)
+            .map_err(|_| UserError::InvalidPasskey)?;
+        if client_data.ceremony != ceremony
+            || client_data.challenge != challenge
+            || !self.origins.contains(&client_data.origin)
+        {
+            return Err(UserError::InvalidPasskey);
+        }
+        Ok(())
+    }
+
+    fn verify_authenticator_data<'a>(
+        &self,
+        auth_data: &'a [u8],
+    ) -> Result<AuthenticatorData<'a>, UserError> {
+        if auth_data.len() < 37 {
+            return Err(UserError::InvalidPasskey);
+        }
+        let auth_data = AuthenticatorData {
+            rp_id_hash: &auth_data[..32],
+            flags: auth_data[32],
+            sign_count: u32::from_be_bytes([
+                auth_data[33],
+                auth_data[34],
+                auth_data[35],
+                auth_data[36],
+            ]),
+            attested_credential: &auth_data[37..],
+        };
+
+        let user_verified = auth_data.flags & FLAG_USER_VERIFIED != 0;
+        if auth_data.rp_id_hash != Sha256::digest(self.id.as_bytes()).as_slice()
+            || auth_data.flags & FLAG_USER_PRESENT == 0
+            || (self.require_user_verification && !user_verified)
+        {
+            return Err(UserError::InvalidPasskey);
+        }
+        Ok(auth_data)
+    }
+}
+
+pub fn relying_party() -> &'static RelyingParty {
+    static RELYING_PARTY: OnceLock<RelyingParty> = OnceLock::new();
+    RELYING_PARTY.get_or_init(RelyingParty::from_env)
+}
+
+/// The opaque user id authenticators keep with a passkey and return on login.
+pub fn user_handle(user_id: Uuid) -> String {
+    URL_SAFE_NO_PAD.encode(user_id.as_bytes())
+}
+
+/// Browsers encode binary fields as unpadded base64url, some libraries pad them.
+fn decode(value: &str) -> Result<Vec<u8>, UserError> {
+    URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .map_err(|_| UserError::InvalidPasskey)
+}
+
+/// Reads an EC2 P-256 COSE key (kty 2, alg -7, crv 1) into a SEC1 point.
+fn es256_public_key(cose_key: &Value) -> Result<Vec<u8>, UserError> {
+    let entries = cose_key.as_map().ok_or(UserError::InvalidPasskey)?;
+    let field = |label: i64| {
+        entries
+            .iter()
+            .find(|(key, _)| {
+                key.as_integer()
+                    .and_then(|key| i64::try_from(key).ok())
+                    .is_some_and(|key| key == label)
+            })
+            .map(|(_, value)| value)
+    };
+    let integer = |label: i64| {
+        field(label)
+            .and_then(Value::as_integer)
+            .and_then(|value| i64::try_from(value).ok())
+    };
+    if integer(1) != Some(2) || integer(3) != Some(ES256) || integer(-1) != Some(1) {
+        return Err(UserError::InvalidPasskey);
+    }
+    let x = field(-2)
+        .and_then(Value::as_bytes)
+        .ok_or(UserError::InvalidPasskey)?;
+    let y = field(-3)
+        .and_then(Value::as_bytes)
+        .ok_or(UserError::InvalidPasskey)?;
+
+    let mut point = vec![0x04];
+    point.extend_from_slice(x);
+    point.extend_from_slice(y);
+    PublicKey::from_sec1_bytes(&point).map_err(|_| UserError::InvalidPasskey)?;
+    Ok(point)
+}
+
+#[cfg(test)]
+mod tests {
+    use p256::ecdsa::signature::Signer;
+    use p256::ecdsa::SigningKey;
+    use p256::elliptic_curve::rand_core::OsRng;
+
+    use super::*;
+
+    const ORIGIN: &str = "https://app.rl.com";
+
+    fn relying_party() -> RelyingParty {
+        RelyingParty {
+            id: "rl.com".to_string(),
+            name: "RL".to_string(),
+            origins: vec![ORIGIN.to_string()],
+            require_user_verification: false,
+        }
+    }
+
+    /// A software authenticator holding one ES256 credential.
+    struct Authenticator {
+        key: SigningKey,
+        credential_id: Vec<u8>,
+        sign_count: u32,
+    }
+
+    impl Authenticator {
+        fn new() -> Self {
+            Self {
+                key: SigningKey::random(&mut OsRng),
+                credential_id: vec![7; 16],
+                sign_count: 0,
+            }
+        }
+
+        fn client_data(ceremony: &str, challenge: &str, origin: &str) -> String {
+            URL_SAFE_NO_PAD.encode(
+                serde_json::json!({ "type": ceremony, "challenge": challenge, "origin": origin })
+                    .to_string(),
+            )
+        }
+
+        fn auth_data(&self, rp_id: &str, flags: u8) -> Vec<u8> {
+            let mut auth_data = Sha256::digest(rp_id.as_bytes()).to_vec();
+            auth_data.push(flags);
+            auth_data.extend_from_slice(&self.sign_count.to_be_bytes());
+            auth_data
+        }
+
+        fn create(&self, challenge: &str, origin: &str) -> (String, String) {
+            let point = self.key.verifying_key().to_encoded_point(false);
+            let cose_key = Value::Map(vec![
+                (Value::from(1), Value::from(2)),
+                (Value::from(3), Value::from(ES256)),
+                (Value::from(-1), Value::from(1)),
+                (Value::from(-2), Value::Bytes(point.x().unwrap().to_vec())),
+                (Value::from(-3), Value::Bytes(point.y().unwrap().to_vec())),
+            ]);
+            let mut auth_data = self.auth_data(
+                "rl.com",
+                FLAG_USER_PRESENT | FLAG_USER_VERIFIED | FLAG_ATTESTED_CREDENTIAL_DATA,
+            );
+            auth_data.extend_from_slice(&[0; 16]);
+            auth_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            auth_data.extend_from_slice(&self.credential_id);
+            ciborium::into_writer(&cose_key, &mut auth_data).unwrap();
+
+            let attestation = Value::Map(vec![
+                (Value::from("fmt"), Value::from("none")),
+                (Value::from("attStmt"), Value::Map(vec![])),
+                (Value::from("authData"), Value::Bytes(auth_data)),
+            ]);
+            let mut attestation_object = Vec::new();
+            ciborium::into_writer(&attestation, &mut attestation_object).unwrap();
+
+            (
+                Self::client_data("webauthn.create", challenge, origin),
+                URL_SAFE_NO_PAD.encode(attestation_object),
+            )
+        }
+
+        fn get(&mut self, challenge: &str, flags: u8) -> (String, String, String) {
+            self.sign_count += 1;
+            let client_data = Self::client_data("webauthn.get", challenge, ORIGIN);
+            let auth_data = self.auth_data("rl.com", flags);
+            let mut signed = auth_data.clone();
+            signed.extend_from_slice(&Sha256::digest(decode(&client_data).unwrap()));
+            let signature: Signature = self.key.sign(&signed);
+
+            (
+                client_data,
+                URL_SAFE_NO_PAD.encode(auth_data),
+                URL_SAFE_NO_PAD.encode(signature.to_der()),
+            )
+        }
+    }
+
+    #[test]
+    fn registers_and_signs_in_with_a_software_authenticator() {
+        let rp = relying_party();
+        let mut authenticator = Authenticator::new();
+
+        let (client_data, attestation_object) = authenticator.create("challenge-1", ORIGIN);
+        let credential = rp
+            .verify_registration("challenge-1", &client_data, &attestation_object)
+            .unwrap();
+        assert_eq!(
+            credential.credential_id,
+            URL_SAFE_NO_PAD.encode(&authenticator.credential_id)
+        );
+
+        let (client_data, auth_data, signature) =
+            authenticator.get("challenge-2", FLAG_USER_PRESENT | FLAG_USER_VERIFIED);
+        let assertion = rp
+            .verify_assertion(
+                "challenge-2",
+                &client_data,
+                &auth_data,
+                &signature,
+                &credential.public_key,
+                credential.sign_count,
+            )
+            .unwrap();
+        assert_eq!(
+            assertion,
+            VerifiedAssertion {
+                sign_count: 1,
+                user_verified: true,
+            }
+        );
+
+        let (client_data, auth_data, signature) =
+            authenticator.get("challenge-3", FLAG_USER_PRESENT);
+        let assertion = rp
+            .verify_assertion(
+                "challenge-3",
+                &client_data,
+                &auth_data,
+                &signature,
+                &credential.public_key,
+                assertion.sign_count,
+            )
+            .unwrap();
+        assert!(!assertion.user_verified);
+    }
+
+    #[test]
+    fn rejects_foreign_origins_replayed_challenges_and_stale_counters() {
+        let rp = relying_party();
+        let mut authenticator = Authenticator::new();
+
+        let (client_data, attestation_object) =
+            authenticator.create("challenge-1", "https://evil.com");
+        assert!(matches!(
+            rp.verify_registration("challenge-1", &client_data, &attestation_object),
+            Err(UserError::InvalidPasskey)
+        ));
+
+        let (client_data, attestation_object) = authenticator.create("challenge-1", ORIGIN);
+        let credential = rp
+            .verify_registration("challenge-1", &client_data, &attestation_object)
+            .unwrap();
+        let (client_data, auth_data, signature) =
+            authenticator.get("challenge-2", FLAG_USER_PRESENT);
+        let verify = |challenge: &str, stored_sign_count: i64| {
+            rp.verify_assertion(
+                challenge,
+                &client_data,
+                &auth_data,
+                &signature,
+                &credential.public_key,
+                stored_sign_count,
+            )
+        };
+
+        assert!(matches!(
+            verify("challenge-3", 0),
+            Err(UserError::InvalidPasskey)
+        ));
+        assert!(matches!(
+            verify("challenge-2", 1),
+            Err(UserError::InvalidPasskey)
+        ));
+        assert!(verify("challenge-2", 0).is_ok());
+    }
+}